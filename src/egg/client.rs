@@ -1,7 +1,11 @@
-use reqwest_streams::{*, error::StreamBodyError};
+use futures::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use reqwest_streams::error::{StreamBodyError, StreamBodyKind};
 
+use crate::events::{Event, EventFilter};
 use crate::process::Output;
 use crate::plans::{CreatePlan, Plan};
+use crate::plans::diff::PlanDiff;
 use crate::tasks::{Task, TaskState};
 
 
@@ -19,9 +23,25 @@ impl Client {
         }
     }
 
+    pub async fn cancel_task(
+        &self,
+        task_id: uuid::Uuid
+    ) -> Result<TaskState, reqwest::Error> {
+        let response = self.reqwest
+            .post(format!("{}/tasks/{}/cancel", self.server, task_id))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
+    }
+
     pub async fn create_plan(&self, plan: &CreatePlan) -> Result<Plan, reqwest::Error> {
         let response = self.reqwest
-            .post(&format!("{}/plans", self.server))
+            .post(format!("{}/plans", self.server))
             .json(plan)
             .send()
             .await?;
@@ -30,12 +50,100 @@ impl Client {
             return Err(response.error_for_status().unwrap_err());
         }
 
-        Ok(response.json().await?)
+        response.json().await
+    }
+
+    /// Subscribe to the server's events that match `filter`. Subscribers
+    /// that fall behind receive `Event::Lagged` in place of the events they
+    /// missed.
+    pub async fn events(
+        &self,
+        filter: &EventFilter
+    ) -> Result<impl Stream<Item = Result<Event, StreamBodyError>>, reqwest::Error> {
+        let response = self.reqwest
+            .get(format!("{}/events", self.server))
+            .query(filter)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        let state = (response.bytes_stream().boxed(), Vec::new());
+        Ok(futures::stream::unfold(state, |(mut body, mut buffer)| async move {
+            loop {
+                // Messages are separated by a blank line
+                if let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+                    let message: Vec<u8> = buffer.drain(..end + 2).collect();
+                    match parse_event(&message) {
+                        Some(event) => {
+                            return Some((event, (body, buffer)));
+                        }
+                        None => continue,
+                    }
+                }
+
+                match body.next().await? {
+                    Ok(bytes) => buffer.extend_from_slice(&bytes),
+                    Err(err) => {
+                        let err = StreamBodyError::new(
+                            StreamBodyKind::InputOutputError,
+                            Some(Box::new(err)),
+                            None
+                        );
+                        return Some((Err(err), (body, buffer)));
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Compare two versions of a plan, by default the newest with the one
+    /// before it.
+    pub async fn diff_plan(
+        &self,
+        plan_id: uuid::Uuid,
+        from: Option<u64>,
+        to: Option<u64>
+    ) -> Result<PlanDiff, reqwest::Error> {
+        let mut query = vec![];
+        if let Some(from) = from {
+            query.push(("from", from));
+        }
+        if let Some(to) = to {
+            query.push(("to", to));
+        }
+
+        let response = self.reqwest
+            .get(format!("{}/plan/{}/diff", self.server, plan_id))
+            .query(&query)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
+    }
+
+    pub async fn get_plan_by_name(&self, name: &str) -> Result<Plan, reqwest::Error> {
+        let response = self.reqwest
+            .get(format!("{}/plans/by-name/{}", self.server, path_segment(name)))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
     }
 
     pub async fn get_task(&self, task_id: uuid::Uuid) -> Result<Task, reqwest::Error> {
         let response = self.reqwest
-            .get(&format!("{}/tasks/{}", self.server, task_id))
+            .get(format!("{}/tasks/{}", self.server, task_id))
             .send()
             .await?;
 
@@ -43,12 +151,40 @@ impl Client {
             return Err(response.error_for_status().unwrap_err());
         }
 
-        Ok(response.json().await?)
+        response.json().await
     }
 
-    pub async fn plan(&self, plan_id: uuid::Uuid) -> Result<Task, reqwest::Error> {
+    pub async fn list_plan_versions(
+        &self,
+        plan_id: uuid::Uuid
+    ) -> Result<Vec<Plan>, reqwest::Error> {
         let response = self.reqwest
-            .post(&format!("{}/plan/{}", self.server, plan_id))
+            .get(format!("{}/plan/{}/versions", self.server, plan_id))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
+    }
+
+    /// Instantiate a plan, at its newest version unless one is given.
+    pub async fn plan(
+        &self,
+        plan_id: uuid::Uuid,
+        version: Option<u64>,
+        params: &BTreeMap<String, serde_json::Value>
+    ) -> Result<Task, reqwest::Error> {
+        let mut request = self.reqwest
+            .post(format!("{}/plan/{}", self.server, plan_id));
+        if let Some(version) = version {
+            request = request.query(&[("version", version)]);
+        }
+
+        let response = request
+            .json(params)
             .send()
             .await?;
 
@@ -56,7 +192,25 @@ impl Client {
             return Err(response.error_for_status().unwrap_err());
         }
 
-        Ok(response.json().await?)
+        response.json().await
+    }
+
+    /// Publish an earlier version of a plan again as its newest version.
+    pub async fn rollback_plan(
+        &self,
+        plan_id: uuid::Uuid,
+        version: u64
+    ) -> Result<Plan, reqwest::Error> {
+        let response = self.reqwest
+            .post(format!("{}/plan/{}/versions/{}/rollback", self.server, plan_id, version))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
     }
 
     pub async fn start_task(
@@ -64,7 +218,52 @@ impl Client {
         task_id: uuid::Uuid
     ) -> Result<TaskState, reqwest::Error> {
         let response = self.reqwest
-            .post(&format!("{}/tasks/{}/start", self.server, task_id))
+            .post(format!("{}/tasks/{}/start", self.server, task_id))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
+    }
+
+    pub async fn update_plan(
+        &self,
+        plan_id: uuid::Uuid,
+        plan: &CreatePlan
+    ) -> Result<Plan, reqwest::Error> {
+        let response = self.reqwest
+            .put(format!("{}/plan/{}", self.server, plan_id))
+            .json(plan)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        response.json().await
+    }
+
+    pub async fn delete_plan(&self, plan_id: uuid::Uuid) -> Result<(), reqwest::Error> {
+        let response = self.reqwest
+            .delete(format!("{}/plan/{}", self.server, plan_id))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        Ok(())
+    }
+
+    /// Delete a finished task tree, given the id of its root task.
+    pub async fn delete_task(&self, task_id: uuid::Uuid) -> Result<(), reqwest::Error> {
+        let response = self.reqwest
+            .delete(format!("{}/tasks/{}", self.server, task_id))
             .send()
             .await?;
 
@@ -72,18 +271,104 @@ impl Client {
             return Err(response.error_for_status().unwrap_err());
         }
 
-        Ok(response.json().await?)
+        Ok(())
     }
 
+    /// Write to the stdin of a running task, closing it afterwards if
+    /// `close` is set.
+    pub async fn write_stdin(
+        &self,
+        task_id: uuid::Uuid,
+        body: impl Into<reqwest::Body>,
+        close: bool
+    ) -> Result<(), reqwest::Error> {
+        let response = self.reqwest
+            .post(format!("{}/tasks/{}/stdin", self.server, task_id))
+            .query(&[("close", close)])
+            .body(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        Ok(())
+    }
+
+    /// Stream the output of a one-based attempt of a task from the entry
+    /// with sequence number `since`. Lines that fail to decode are
+    /// `InvalidData` errors; any other error means the connection broke off.
     pub async fn tail_task(
         &self,
-        task_id: uuid::Uuid
-    ) -> Result<impl futures::Stream<Item = Result<Output, StreamBodyError>>, reqwest::Error> {
-        Ok(self.reqwest
-            .get(&format!("{}/tasks/{}/output", self.server, task_id))
+        task_id: uuid::Uuid,
+        attempt: usize,
+        since: u64
+    ) -> Result<impl futures::Stream<Item = Result<Output, std::io::Error>>, reqwest::Error> {
+        let response = self.reqwest
+            .get(format!("{}/tasks/{}/output", self.server, task_id))
+            .query(&[("attempt", attempt as u64), ("since", since)])
             .send()
-            .await?
-            .json_nl_stream::<Output>(16384)
-        )
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(response.error_for_status().unwrap_err());
+        }
+
+        // Lines are as long as the command wrote them, so they are not
+        // limited in length
+        let state = (response.bytes_stream().boxed(), Vec::new());
+        Ok(futures::stream::unfold(state, |(mut body, mut buffer)| async move {
+            loop {
+                if let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..end + 1).collect();
+                    let output = serde_json::from_slice(&line).map_err(|err| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+                    });
+                    return Some((output, (body, buffer)));
+                }
+
+                match body.next().await? {
+                    Ok(bytes) => buffer.extend_from_slice(&bytes),
+                    Err(err) => {
+                        return Some((Err(std::io::Error::other(err)), (body, buffer)));
+                    }
+                }
+            }
+        }))
     }
 }
+
+
+/// Decode the data of a server-sent event, skipping keep-alive comments.
+fn parse_event(message: &[u8]) -> Option<Result<Event, StreamBodyError>> {
+    let message = String::from_utf8_lossy(message);
+    let data: Vec<&str> = message
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+
+    if data.is_empty() {
+        return None;
+    }
+
+    Some(serde_json::from_str(&data.join("\n")).map_err(|err| {
+        StreamBodyError::new(StreamBodyKind::CodecError, Some(Box::new(err)), None)
+    }))
+}
+
+
+/// Percent-encode text for use as a single segment of a URL path.
+fn path_segment(text: &str) -> String {
+    let mut segment = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            segment.push(byte as char);
+        } else {
+            segment.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    segment
+}
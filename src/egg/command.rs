@@ -1,4 +1,5 @@
 use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
 use uuid::Uuid;
 
 mod run;
@@ -17,13 +18,50 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 pub enum Command {
+    /// Create a named plan, or update it if its definition changed
+    #[clap(name = "apply")]
+    Apply {
+        filename: String,
+        #[clap(short, long, default_value = "http://127.0.0.1:3000")]
+        server: String,
+    },
+    #[clap(name = "cancel")]
+    Cancel {
+        id: Uuid,
+        #[clap(short, long, default_value = "http://127.0.0.1:3000")]
+        server: String,
+    },
     #[clap(name = "create")]
     Create(Create),
+    /// Show what changed between two versions of a plan, by default the
+    /// newest and the one before it
+    #[clap(name = "diff")]
+    Diff {
+        id: Uuid,
+        from: Option<u64>,
+        to: Option<u64>,
+        #[clap(short, long, default_value = "http://127.0.0.1:3000")]
+        server: String,
+    },
     #[clap(name = "plan")]
     Plan {
         id: Uuid,
         #[clap(short, long, default_value = "http://127.0.0.1:3000")]
         server: String,
+        /// Set a plan parameter, as name=value
+        #[clap(long = "param", value_parser = parse_param)]
+        params: Vec<(String, String)>,
+        /// Instantiate this version of the plan instead of the newest
+        #[clap(long)]
+        version: Option<u64>,
+    },
+    /// Publish an earlier version of a plan again as its newest version
+    #[clap(name = "rollback")]
+    Rollback {
+        id: Uuid,
+        version: u64,
+        #[clap(short, long, default_value = "http://127.0.0.1:3000")]
+        server: String,
     },
     #[clap(name = "serve")]
     Serve {
@@ -31,6 +69,27 @@ pub enum Command {
         bind: String,
         #[clap(short, long, default_value = "3000")]
         port: u16,
+        /// Seconds to wait after SIGTERM before killing a timed out command
+        #[clap(long, default_value = "10")]
+        kill_grace: u64,
+        /// Keep plans, tasks and their output in this directory across restarts
+        #[clap(long)]
+        data_dir: Option<PathBuf>,
+        /// Delete output logs older than this many seconds
+        #[clap(long, requires = "data_dir")]
+        output_max_age: Option<u64>,
+        /// Delete the oldest output logs once they exceed this many bytes
+        #[clap(long, requires = "data_dir")]
+        output_max_size: Option<u64>,
+        /// Run at most this many commands at once, queueing the rest
+        #[clap(long)]
+        max_running: Option<usize>,
+        /// Delete finished task trees and their output after this many seconds
+        #[clap(long)]
+        task_ttl: Option<u64>,
+        /// Keep at most this many finished task trees, deleting the oldest
+        #[clap(long)]
+        max_finished_trees: Option<usize>,
     },
     #[clap(name = "start")]
     Start {
@@ -76,6 +135,7 @@ pub enum Error {
     Io(std::io::Error),
     Reqwest(reqwest::Error),
     Serde(serde_yaml::Error),
+    Server(crate::error::Error),
 }
 
 impl From<std::io::Error> for Error {
@@ -90,8 +150,22 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<crate::error::Error> for Error {
+    fn from(err: crate::error::Error) -> Self {
+        Error::Server(err)
+    }
+}
+
 impl From<serde_yaml::Error> for Error {
     fn from(err: serde_yaml::Error) -> Self {
         Error::Serde(err)
     }
 }
+
+
+fn parse_param(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => Err(format!("expected name=value, got {}", arg)),
+    }
+}
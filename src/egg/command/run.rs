@@ -1,45 +1,144 @@
 use clap::Parser;
-use futures::StreamExt;
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::future::Future;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::egg::client::Client;
 use crate::egg::command::{Cli, Command, Create, CreateCommand, Error};
-use crate::plans::{CreatePlan, Plan};
-use crate::process::Output;
-use crate::tasks::{Task, TaskSpec, TaskState};
+use crate::egg::server::Server;
+use crate::egg::server::collect::TaskRetention;
+use crate::egg::server::output::OutputSpool;
+use crate::egg::server::storage::{DiskStorage, MemoryStorage, Storage};
+use crate::plans::{CreatePlan, Plan, PlanSpec};
+use crate::plans::diff::Change;
+use crate::process::OutputEvent;
+use crate::tasks::{Task, TaskSpec, TaskState, TaskStatus};
 
 
 pub async fn run() -> Result<(), Error> {
     let args = Cli::parse();
     match args.command {
+        Command::Apply { filename, server } => {
+            apply(filename, server, args.verbose).await?;
+        }
+        Command::Cancel { id, server } => {
+            cancel(id, server, args.verbose).await?;
+        }
         Command::Create(Create { command }) => match command {
             CreateCommand::Plan { filename, server } => {
                 create_plan(filename, server, args.verbose).await?;
             }
         }
-        Command::Plan { id, server } => {
-            plan(id, server, args.verbose).await?;
+        Command::Diff { id, from, to, server } => {
+            diff(id, from, to, server).await?;
+        }
+        Command::Plan { id, server, params, version } => {
+            plan(id, server, params, version, args.verbose).await?;
+        }
+        Command::Rollback { id, version, server } => {
+            rollback(id, version, server).await?;
         }
-        Command::Serve { bind, port } => {
-            serve(bind, port, args.verbose).await?;
+        Command::Serve {
+            bind,
+            port,
+            kill_grace,
+            data_dir,
+            output_max_age,
+            output_max_size,
+            max_running,
+            task_ttl,
+            max_finished_trees,
+        } => {
+            let output = data_dir.as_ref().map(|dir| OutputSpool {
+                dir: dir.join("output"),
+                max_age: output_max_age.map(Duration::from_secs),
+                max_size: output_max_size,
+            });
+            let retention = TaskRetention {
+                ttl: task_ttl.map(Duration::from_secs),
+                max_finished: max_finished_trees,
+            };
+
+            let addr = format!("{}:{}", bind, port);
+            serve(addr, kill_grace, data_dir, output, retention, max_running, args.verbose).await?;
         }
         Command::Start { id, server } => {
             start(id, server, args.verbose).await?;
         }
         Command::Run { id, server } => {
-            run_task(id, server, args.verbose).await?;
+            exit(run_task(id, server, args.verbose).await?);
         }
         Command::Tail { id, server } => {
-            tail_task(id, server, args.verbose).await?;
+            exit(tail_task(id, server, None, args.verbose).await?);
         }
     }
     Ok(())
 }
 
 
+/// Exit with the exit code of a command that was run or tailed.
+fn exit(code: i32) {
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+
+async fn cancel(id: Uuid, server: String, verbose: bool) -> Result<(), Error> {
+    let task: TaskState = Client::new(server).cancel_task(id).await?;
+    if verbose {
+        println!("{:?}", task);
+    }
+
+    Ok(())
+}
+
+
+async fn apply(filename: String, server: String, verbose: bool) -> Result<(), Error> {
+    let definition: CreatePlan = serde_yaml::from_str(&std::fs::read_to_string(filename)?)?;
+    let name = definition.name.clone().ok_or_else(|| {
+        crate::error::Error::InvalidPlan("Plan has no name".to_string())
+    })?;
+
+    let client = Client::new(server);
+    let plan = match client.get_plan_by_name(&name).await {
+        Ok(plan) => plan,
+        Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            let plan = client.create_plan(&definition).await?;
+            println!("Created plan {} ({})", name, plan.id);
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+    };
+
+    let current = CreatePlan {
+        name: plan.name.clone(),
+        spec: plan.spec.clone(),
+        deadline: plan.deadline,
+        params: plan.params.clone(),
+    };
+
+    if current == definition {
+        println!("Plan {} ({}) is unchanged", name, plan.id);
+        return Ok(());
+    }
+
+    let plan = client.update_plan(plan.id, &definition).await?;
+    if verbose {
+        println!("{:?}", plan);
+    }
+
+    println!("Updated plan {} ({}) to version {}", name, plan.id, plan.version);
+    Ok(())
+}
+
+
 async fn create_plan(
     filename: String,
     server: String,
@@ -55,8 +154,75 @@ async fn create_plan(
 }
 
 
-async fn plan(id: Uuid, server: String, verbose: bool) -> Result<(), Error> {
-    let task: Task = Client::new(server).plan(id).await?;
+async fn diff(
+    id: Uuid,
+    from: Option<u64>,
+    to: Option<u64>,
+    server: String
+) -> Result<(), Error> {
+    let diff = Client::new(server).diff_plan(id, from, to).await?;
+    println!("Plan {} version {} -> {}", id, diff.from, diff.to);
+    if diff.changes.is_empty() {
+        println!("No changes");
+    }
+
+    for change in diff.changes {
+        match change {
+            Change::Added { path, node } => {
+                println!("+ {}: {}", path, compact(&node));
+            }
+            Change::Removed { path, node } => {
+                println!("- {}: {}", path, compact(&node));
+            }
+            Change::Changed { path, field, from, to } => {
+                let path = if path.is_empty() { field } else { format!("{}.{}", path, field) };
+                println!("~ {}: {} -> {}", path, from, to);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Render a plan node on one line, leaving out settings left at defaults.
+fn compact(node: &PlanSpec) -> String {
+    fn strip(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(fields) => {
+                serde_json::Value::Object(fields.into_iter()
+                    .map(|(key, value)| (key, strip(value)))
+                    .filter(|(_, value)| match value {
+                        serde_json::Value::Null | serde_json::Value::Bool(false) => false,
+                        serde_json::Value::Object(fields) => !fields.is_empty(),
+                        _ => true,
+                    })
+                    .collect())
+            }
+            serde_json::Value::Array(values) => {
+                serde_json::Value::Array(values.into_iter().map(strip).collect())
+            }
+            value => value,
+        }
+    }
+
+    let node = serde_json::to_value(node).unwrap_or_default();
+    serde_json::to_string(&strip(node)).unwrap_or_default()
+}
+
+
+async fn plan(
+    id: Uuid,
+    server: String,
+    params: Vec<(String, String)>,
+    version: Option<u64>,
+    verbose: bool
+) -> Result<(), Error> {
+    // The server parses values into the types the plan declares
+    let params = params.into_iter()
+        .map(|(name, value)| (name, serde_json::Value::String(value)))
+        .collect();
+    let task: Task = Client::new(server).plan(id, version, &params).await?;
     if verbose {
         println!("{:?}", task);
     }
@@ -65,9 +231,37 @@ async fn plan(id: Uuid, server: String, verbose: bool) -> Result<(), Error> {
 }
 
 
-async fn serve(bind: String, port: u16, verbose: bool) -> Result<(), std::io::Error> {
-    let addr = format!("{}:{}", bind, port);
-    let server = Arc::new(crate::egg::server::Server::new(verbose));
+async fn rollback(id: Uuid, version: u64, server: String) -> Result<(), Error> {
+    let plan = Client::new(server).rollback_plan(id, version).await?;
+    println!("Published version {} of plan {} as version {}", version, id, plan.version);
+    Ok(())
+}
+
+
+async fn serve(
+    addr: String,
+    kill_grace: u64,
+    data_dir: Option<PathBuf>,
+    output: Option<OutputSpool>,
+    retention: TaskRetention,
+    max_running: Option<usize>,
+    verbose: bool
+) -> Result<(), Error> {
+    let storage: Box<dyn Storage> = match data_dir {
+        Some(dir) => Box::new(DiskStorage::new(dir)?),
+        None => Box::new(MemoryStorage),
+    };
+
+    let server = Arc::new(Server::new(
+        storage,
+        output,
+        retention,
+        verbose,
+        Duration::from_secs(kill_grace),
+        max_running,
+    ));
+    server.restore().await?;
+
     let listener = tokio::net::TcpListener::bind(addr).await?;
     crate::egg::server::serve(server, listener).await?;
     Ok(())
@@ -84,17 +278,21 @@ async fn start(id: Uuid, server: String, verbose: bool) -> Result<(), Error> {
 }
 
 
-async fn run_task(id: Uuid, server: String, verbose: bool) -> Result<(), Error> {
+async fn run_task(id: Uuid, server: String, verbose: bool) -> Result<i32, Error> {
     start(id, server.clone(), verbose).await?;
-    tail_task(id, server, verbose).await
+    tail_task(id, server, None, verbose).await
 }
 
 
+/// Print a task's output until it finishes, returning zero if it succeeded
+/// or else the exit code of the first command that failed. Output of named
+/// tasks is labelled with their names.
 fn tail_task(
     id: Uuid,
     server: String,
+    label: Option<String>,
     verbose: bool
-) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+) -> Pin<Box<dyn Future<Output = Result<i32, Error>> + Send>> {
     Box::pin(async move {
         let client = Client::new(server.clone());
         let task = client.get_task(id).await?;
@@ -103,91 +301,222 @@ fn tail_task(
             println!("Tailing task: {:?}", task);
         }
 
-        match task.spec {
-            TaskSpec::Command { .. } => {
-                tail_command(id, server).await?;
+        let label = match (label, task.name) {
+            (Some(label), Some(name)) => Some(format!("{} / {}", label, name)),
+            (label, name) => name.or(label),
+        };
+
+        let code = match task.spec {
+            TaskSpec::Command { .. } | TaskSpec::Script { .. } => {
+                return tail_command(id, server, label).await;
             }
-            TaskSpec::TaskGroup { parallel } => {
-                tail_parallel(parallel, server, verbose).await?;
+            TaskSpec::TaskGroup { parallel, .. } => {
+                tail_parallel(parallel, server, label, verbose).await?
             }
-            TaskSpec::TaskList { serial } => {
-                tail_serial(serial, server, verbose).await?;
+            TaskSpec::TaskList { serial, continue_on_error, .. } => {
+                tail_serial(serial, continue_on_error, server, label, verbose).await?
             }
-        }
-
-        Ok(())
+            TaskSpec::Steps { steps, .. } => {
+                let steps = steps.iter().map(|step| step.task).collect();
+                tail_parallel(steps, server, label, verbose).await?
+            }
+        };
+
+        // The children's codes only tell which command failed; whether the
+        // task failed depends on its own policies and retries
+        let task = wait_finished(&client, id).await?;
+        Ok(match task.status {
+            TaskStatus::Success => 0,
+            _ if code != 0 => code,
+            _ => exit_code(&task),
+        })
     })
 }
 
 
+/// Delay before reconnecting a dropped output stream.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+
+/// Print the output of each attempt of a command until the command
+/// finishes, returning its final exit code.
 async fn tail_command(
     id: Uuid,
     server: String,
-) -> Result<(), Error> {
+    label: Option<String>,
+) -> Result<i32, Error> {
     let client = Client::new(server);
-    let stream = client.tail_task(id).await?;
-
-    let _ = stream.map(|line| {
-        match line {
-            Ok(line) => {
-                match line {
-                    Output::Stdout(line) => {
-                        println!("{}", line);
-                    }
-                    Output::Stderr(line) => {
-                        eprintln!("{}", line);
-                    }
+    let prefix = label.map(|label| format!("[{}] ", label)).unwrap_or_default();
+    let mut attempt = 1;
+    let mut since = 0;
+
+    loop {
+        let mut stream = match client.tail_task(id, attempt, since).await {
+            Ok(stream) => Box::pin(stream),
+            Err(err) if err.is_connect() || err.is_timeout() || err.is_request() => {
+                eprintln!("Error: {}, reconnecting", err);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+            Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                // An attempt has no output until it starts, and never will
+                // if the command finished without running it
+                let task = client.get_task(id).await?;
+                if attempt < task.attempts.len() {
+                    attempt += 1;
+                    since = 0;
+                } else if task.status.is_finished() {
+                    return Ok(exit_code(&task));
+                } else {
+                    tokio::time::sleep(RECONNECT_DELAY).await;
                 }
+                continue;
             }
             Err(err) => {
-                eprintln!("Error: {}", err);
+                return Err(err.into());
+            }
+        };
+
+        // Resume after the last line received if the stream breaks off
+        let mut dropped = false;
+        while let Some(line) = stream.next().await {
+            match line {
+                Ok(output) => {
+                    since = output.seq + 1;
+                    match output.event {
+                        OutputEvent::Stdout(line) => {
+                            println!("{}{}", prefix, line);
+                        }
+                        OutputEvent::Stderr(line) => {
+                            eprintln!("{}{}", prefix, line);
+                        }
+                        OutputEvent::Exit { code: Some(exit_code), duration, .. } => {
+                            eprintln!(
+                                "{}Exited with code {} in {:.2?}",
+                                prefix,
+                                exit_code,
+                                duration
+                            );
+                        }
+                        OutputEvent::Exit { signal, duration, .. } => {
+                            eprintln!(
+                                "{}Killed by signal {} after {:.2?}",
+                                prefix,
+                                signal.unwrap_or(0),
+                                duration
+                            );
+                        }
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                    return Err(err.into());
+                }
+                Err(err) => {
+                    eprintln!("Error: {}, reconnecting", err);
+                    dropped = true;
+                    break;
+                }
             }
         }
-    }).collect::<()>().await;
 
-    Ok(())
+        if dropped {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+
+        // The stream ends with the attempt, which may not be the last one.
+        // Streams that end without an exit record, such as those of
+        // commands that failed to spawn, are settled by the task's status.
+        let task = client.get_task(id).await?;
+        if attempt >= task.attempts.len() && task.status.is_finished() {
+            return Ok(exit_code(&task));
+        }
+
+        attempt += 1;
+        since = 0;
+    }
+}
+
+
+/// Poll a task until it has finished.
+async fn wait_finished(client: &Client, id: Uuid) -> Result<Task, Error> {
+    loop {
+        let task = client.get_task(id).await?;
+        if task.status.is_finished() {
+            return Ok(task);
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+
+/// The exit code for a finished task, which is non-zero unless it succeeded.
+fn exit_code(task: &Task) -> i32 {
+    match (&task.status, task.exit_code, task.signal) {
+        (TaskStatus::Success, _, _) => 0,
+        (_, Some(code), _) if code != 0 => code,
+        (_, _, Some(signal)) => 128 + signal,
+        _ => 1,
+    }
 }
 
 
 async fn tail_parallel(
     parallel: Vec<Uuid>,
     server: String,
+    label: Option<String>,
     verbose: bool
-) -> Result<(), Error> {
+) -> Result<i32, Error> {
     // dispatch each task to a separate thread
-    let mut handles = vec![];
+    let mut handles = FuturesUnordered::new();
 
     for id in parallel {
         let server = server.clone();
+        let label = label.clone();
         let handle = tokio::spawn(async move {
-            tail_task(id, server, verbose).await
+            tail_task(id, server, label, verbose).await
         });
 
         handles.push(handle);
     }
 
-    // wait for all tasks to finish
-    for handle in handles {
-        match handle.await {
-            Ok(_) => {}
+    // wait for all tasks to finish, keeping the code of the first failure
+    let mut code = 0;
+    while let Some(handle) = handles.next().await {
+        match handle {
+            Ok(Ok(child)) => {
+                if code == 0 {
+                    code = child;
+                }
+            }
+            Ok(Err(err)) => {
+                eprintln!("Error: {:?}", err);
+            }
             Err(err) => {
                 eprintln!("Error: {}", err);
             }
         }
     }
 
-    Ok(())
+    Ok(code)
 }
 
 
 async fn tail_serial(
     serial: Vec<Uuid>,
+    continue_on_error: bool,
     server: String,
+    label: Option<String>,
     verbose: bool
-) -> Result<(), Error> {
+) -> Result<i32, Error> {
     for id in serial {
-        tail_task(id, server.clone(), verbose).await?;
+        // The rest of the list does not run after a failure
+        let code = tail_task(id, server.clone(), label.clone(), verbose).await?;
+        if code != 0 && !continue_on_error {
+            return Ok(code);
+        }
     }
 
-    Ok(())
+    Ok(0)
 }
@@ -1,32 +1,161 @@
 use axum::routing::{get, post};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, Mutex, Notify, Semaphore};
 use uuid::Uuid;
 
+use crate::egg::server::collect::TaskRetention;
+use crate::egg::server::output::OutputSpool;
+use crate::egg::server::storage::{PlanRecord, Storage, StorageWrite, StorageWriter, TaskRecord};
 use crate::error::Error;
-use crate::plans::PlanSpec;
-use crate::process::Process;
-use crate::tasks::{TaskPlan, TaskSpec, TaskStatus};
+use crate::events::Event;
+use crate::plans::CreatePlan;
+use crate::process::{Process, Rusage};
+use crate::tasks::{Task, TaskAttempt, TaskPlan, TaskSpec, TaskStatus};
 
+pub mod collect;
 mod handlers;
+pub mod output;
 mod plan;
 mod run;
+pub mod storage;
+
+
+/// Number of events kept for subscribers that fall behind.
+const EVENT_CAPACITY: usize = 1024;
 
 
 pub struct Server {
     pub plans: Mutex<HashMap<Uuid, Arc<Mutex<ServerPlan>>>>,
     pub tasks: Mutex<HashMap<Uuid, Arc<Mutex<ServerTask>>>>,
+    pub storage: Arc<dyn Storage>,
+    pub writer: StorageWriter,
+    pub output: Option<OutputSpool>,
+    pub retention: TaskRetention,
+    pub events: broadcast::Sender<Event>,
+    /// Limits the number of commands running at once
+    pub slots: Option<Arc<Semaphore>>,
     pub verbose: bool,
+    pub kill_grace: Duration,
 }
 
 impl Server {
-    pub fn new(verbose: bool) -> Self {
+    pub fn new(
+        storage: Box<dyn Storage>,
+        output: Option<OutputSpool>,
+        retention: TaskRetention,
+        verbose: bool,
+        kill_grace: Duration,
+        max_running: Option<usize>
+    ) -> Self {
+        let storage: Arc<dyn Storage> = Arc::from(storage);
+
         Self {
             plans: Mutex::new(HashMap::new()),
             tasks: Mutex::new(HashMap::new()),
+            writer: StorageWriter::spawn(storage.clone()),
+            storage,
+            output,
+            retention,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            slots: max_running.map(|slots| Arc::new(Semaphore::new(slots.max(1)))),
             verbose,
+            kill_grace,
+        }
+    }
+
+    /// Load plans and tasks from storage. Tasks that were still in progress
+    /// when the previous server stopped are marked as interrupted, along
+    /// with their unfinished descendants, and finished tasks without a
+    /// finish time count as finished now.
+    pub async fn restore(&self) -> Result<(), Error> {
+        let (plans, tasks) = self.storage.load()?;
+
+        let mut server_plans = self.plans.lock().await;
+        for plan in plans {
+            server_plans.insert(plan.id, Arc::new(Mutex::new(ServerPlan {
+                versions: plan.versions,
+                created_at: plan.created_at.unwrap_or_else(SystemTime::now),
+            })));
         }
+
+        let mut restored: HashMap<Uuid, ServerTask> = tasks.into_iter()
+            .map(|record| (record.task.id, ServerTask::restore(record)))
+            .collect();
+
+        let mut interrupted: Vec<Uuid> = restored.iter()
+            .filter(|(_, task)| {
+                matches!(task.status, TaskStatus::Queued | TaskStatus::Running | TaskStatus::Waiting)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        // Pending descendants would otherwise wait for a parent that never
+        // resumes, as they do when a parent is terminated
+        while let Some(id) = interrupted.pop() {
+            let Some(task) = restored.get_mut(&id) else {
+                continue;
+            };
+
+            if task.status.is_finished() {
+                continue;
+            }
+
+            task.status = TaskStatus::Interrupted;
+            task.finished_at = Some(SystemTime::now());
+            self.persist_task(id, task);
+            interrupted.extend(task.spec.children());
+        }
+
+        let mut server_tasks = self.tasks.lock().await;
+        for (id, mut task) in restored {
+            if task.status.is_finished() && task.finished_at.is_none() {
+                task.finished_at = Some(SystemTime::now());
+                self.persist_task(id, &task);
+            }
+
+            server_tasks.insert(id, Arc::new(Mutex::new(task)));
+        }
+
+        Ok(())
+    }
+
+    /// Publish an event to every subscriber of the event stream.
+    pub fn publish(&self, event: Event) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    pub fn publish_status(&self, id: Uuid, task: &ServerTask) {
+        self.publish(Event::TaskStatus {
+            id,
+            plan: task.plan.clone(),
+            status: task.status.clone(),
+        });
+    }
+
+    pub fn persist_plan(&self, id: Uuid, plan: &ServerPlan) {
+        let record = PlanRecord {
+            id,
+            versions: plan.versions.clone(),
+            created_at: Some(plan.created_at),
+        };
+        self.writer.write(StorageWrite::SavePlan(record));
+    }
+
+    pub fn persist_task(&self, id: Uuid, task: &ServerTask) {
+        let record = TaskRecord { task: task.task(id), deadline: task.deadline };
+        self.writer.write(StorageWrite::SaveTask(Box::new(record)));
+    }
+
+    pub fn unpersist_plan(&self, id: Uuid) {
+        self.writer.write(StorageWrite::DeletePlan(id));
+    }
+
+    pub fn unpersist_task(&self, id: Uuid) {
+        self.writer.write(StorageWrite::DeleteTask(id));
     }
 }
 
@@ -34,8 +163,14 @@ impl Server {
 pub enum ServerError {
     InternalServerError,
     PlanNotFound(Uuid),
+    PlanNameNotFound(String),
+    PlanVersionNotFound(Uuid, u64),
+    PlanExists(String),
     TaskNotFound(Uuid),
+    TaskRunning(Uuid),
     InvalidTaskState(Uuid),
+    InvalidPlan(String),
+    InvalidParams(String),
 }
 
 impl axum::response::IntoResponse for ServerError {
@@ -53,18 +188,54 @@ impl axum::response::IntoResponse for ServerError {
                     format!("Plan not found: {:?}", id)
                 ).into_response()
             }
+            ServerError::PlanNameNotFound(name) => {
+                (
+                    axum::http::StatusCode::NOT_FOUND,
+                    format!("Plan not found: {}", name)
+                ).into_response()
+            }
+            ServerError::PlanVersionNotFound(id, version) => {
+                (
+                    axum::http::StatusCode::NOT_FOUND,
+                    format!("Plan version not found: {:?} version {}", id, version)
+                ).into_response()
+            }
+            ServerError::PlanExists(name) => {
+                (
+                    axum::http::StatusCode::CONFLICT,
+                    format!("Plan already exists: {}", name)
+                ).into_response()
+            }
             ServerError::TaskNotFound(id) => {
                 (
                     axum::http::StatusCode::NOT_FOUND,
                     format!("Task not found: {:?}", id)
                 ).into_response()
             }
+            ServerError::TaskRunning(id) => {
+                (
+                    axum::http::StatusCode::CONFLICT,
+                    format!("Task is still running: {:?}", id)
+                ).into_response()
+            }
             ServerError::InvalidTaskState(id) => {
                 (
                     axum::http::StatusCode::BAD_REQUEST,
                     format!("Invalid task state: {:?}", id)
                 ).into_response()
             }
+            ServerError::InvalidPlan(reason) => {
+                (
+                    axum::http::StatusCode::BAD_REQUEST,
+                    format!("Invalid plan: {}", reason)
+                ).into_response()
+            }
+            ServerError::InvalidParams(reason) => {
+                (
+                    axum::http::StatusCode::BAD_REQUEST,
+                    format!("Invalid parameters: {}", reason)
+                ).into_response()
+            }
         }
     }
 }
@@ -72,18 +243,168 @@ impl axum::response::IntoResponse for ServerError {
 
 #[derive(Debug)]
 pub struct ServerPlan {
-    pub versions: Vec<PlanSpec>,
+    pub versions: Vec<CreatePlan>,
+    pub created_at: SystemTime,
+}
+
+
+#[derive(Debug)]
+pub struct ServerAttempt {
+    pub status: TaskStatus,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub process: Option<Arc<Process>>,
 }
 
 
 #[derive(Debug)]
 pub struct ServerTask {
+    pub name: Option<String>,
     pub plan: Option<TaskPlan>,
     pub spec: TaskSpec,
     pub status: TaskStatus,
     pub running: Option<Arc<Process>>,
     pub finished: Arc<Notify>,
     pub error: Option<Error>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// Bounds the whole task, including any retries. Set on the root task
+    /// from the plan's deadline; command timeouts bound single attempts.
+    pub deadline: Option<Duration>,
+    /// Earlier attempts that failed and were retried
+    pub attempts: Vec<ServerAttempt>,
+    pub params: BTreeMap<String, serde_json::Value>,
+    pub created_at: SystemTime,
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+    /// Resource usage of a command, summed over its attempts
+    pub rusage: Option<Rusage>,
+}
+
+impl ServerTask {
+    pub fn new(plan: Option<TaskPlan>, spec: TaskSpec) -> Self {
+        Self {
+            name: None,
+            plan,
+            spec,
+            status: TaskStatus::Pending,
+            running: None,
+            finished: Arc::new(Notify::new()),
+            error: None,
+            exit_code: None,
+            signal: None,
+            deadline: None,
+            attempts: vec![],
+            params: BTreeMap::new(),
+            created_at: SystemTime::now(),
+            started_at: None,
+            finished_at: None,
+            rusage: None,
+        }
+    }
+
+    pub fn restore(record: TaskRecord) -> Self {
+        let task = record.task;
+        let mut attempts: Vec<ServerAttempt> = task.attempts.into_iter()
+            .map(|attempt| ServerAttempt {
+                status: attempt.status,
+                exit_code: attempt.exit_code,
+                signal: attempt.signal,
+                process: None,
+            })
+            .collect();
+
+        // The last attempt is the current one, which lives on the task
+        if task.status != TaskStatus::Pending {
+            attempts.pop();
+        }
+
+        Self {
+            name: task.name,
+            plan: task.plan,
+            spec: task.spec,
+            status: task.status,
+            running: None,
+            finished: Arc::new(Notify::new()),
+            error: None,
+            exit_code: task.exit_code,
+            signal: task.signal,
+            deadline: record.deadline,
+            attempts,
+            params: task.params,
+            created_at: task.created_at.unwrap_or_else(SystemTime::now),
+            started_at: task.started_at,
+            finished_at: task.finished_at,
+            rusage: task.rusage,
+        }
+    }
+
+    pub fn task(&self, id: Uuid) -> Task {
+        Task {
+            id,
+            name: self.name.clone(),
+            plan: self.plan.clone(),
+            spec: self.spec.clone(),
+            status: self.status.clone(),
+            exit_code: self.exit_code,
+            signal: self.signal,
+            attempts: self.attempts(),
+            params: self.params.clone(),
+            created_at: Some(self.created_at),
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            duration: self.duration(),
+            rusage: self.rusage.clone(),
+        }
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        let finished_at = self.finished_at.unwrap_or_else(SystemTime::now);
+        Some(finished_at.duration_since(self.started_at?).unwrap_or_default())
+    }
+
+    /// Every attempt made so far, ending with the current one.
+    pub fn attempts(&self) -> Vec<TaskAttempt> {
+        let mut attempts: Vec<TaskAttempt> = self.attempts.iter()
+            .map(|attempt| TaskAttempt {
+                status: attempt.status.clone(),
+                exit_code: attempt.exit_code,
+                signal: attempt.signal,
+            })
+            .collect();
+
+        if self.status != TaskStatus::Pending {
+            attempts.push(TaskAttempt {
+                status: self.status.clone(),
+                exit_code: self.exit_code,
+                signal: self.signal,
+            });
+        }
+
+        attempts
+    }
+
+    /// The process of a one-based attempt, or of the current attempt.
+    pub fn process(&self, attempt: Option<usize>) -> Option<Arc<Process>> {
+        match attempt {
+            None => self.running.clone(),
+            Some(n) if n == self.attempts.len() + 1 => self.running.clone(),
+            Some(n) => self.attempts.get(n.checked_sub(1)?)?.process.clone(),
+        }
+    }
+
+    /// Record the current attempt as finished with the given status and
+    /// clear its results so that the task can run again.
+    pub fn reset(&mut self, status: TaskStatus) {
+        self.attempts.push(ServerAttempt {
+            status,
+            exit_code: self.exit_code.take(),
+            signal: self.signal.take(),
+            process: self.running.take(),
+        });
+        self.error = None;
+        self.finished_at = None;
+    }
 }
 
 
@@ -95,12 +416,47 @@ pub async fn serve(
         .route("/plan/:plan_id",
             get(handlers::get_plan)
             .post(handlers::plan)
-            .put(handlers::update_plan))
+            .put(handlers::update_plan)
+            .delete(handlers::delete_plan))
+        .route("/plan/:plan_id/diff", get(handlers::diff_plan))
+        .route("/plan/:plan_id/versions", get(handlers::list_plan_versions))
+        .route("/plan/:plan_id/versions/:version", get(handlers::get_plan_version))
+        .route("/plan/:plan_id/versions/:version/rollback", post(handlers::rollback_plan))
+        .route("/events", get(handlers::events))
         .route("/plans", get(handlers::list_plans).post(handlers::create_plan))
+        .route("/plans/by-name/:name",
+            get(handlers::get_plan_by_name)
+            .post(handlers::plan_by_name)
+            .put(handlers::update_plan_by_name)
+            .delete(handlers::delete_plan_by_name))
         .route("/tasks", get(handlers::list_tasks).post(handlers::create_task))
-        .route("/tasks/:task_id", get(handlers::get_task))
+        .route("/tasks/:task_id", get(handlers::get_task).delete(handlers::delete_task))
         .route("/tasks/:task_id/output", get(handlers::task_output_stream))
         .route("/tasks/:task_id/start", post(handlers::start_task))
-        .with_state(server);
-    axum::serve(listener, app).await
+        .route("/tasks/:task_id/stdin", post(handlers::task_stdin))
+        .route("/tasks/:task_id/cancel", post(handlers::cancel_task))
+        .with_state(server.clone());
+    tokio::spawn(output::retain(server.clone()));
+    tokio::spawn(collect::collect(server.clone()));
+
+    // Open streams such as events would hold off a graceful shutdown, so
+    // the server stops as soon as it is asked to
+    let result = tokio::select! {
+        result = axum::serve(listener, app) => result,
+        result = shutdown_signal() => result,
+    };
+
+    // Apply the changes still queued before the process exits
+    let _ = tokio::task::spawn_blocking(move || server.writer.close()).await;
+    result
+}
+
+
+/// Wait for SIGINT or SIGTERM.
+async fn shutdown_signal() -> Result<(), std::io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
 }
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use crate::egg::server::{Server, ServerError};
+use crate::tasks::TaskStatus;
+
+
+/// How often finished task trees are collected.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(60);
+
+
+/// How long finished task trees are kept, by age and by count.
+#[derive(Clone, Debug, Default)]
+pub struct TaskRetention {
+    pub ttl: Option<Duration>,
+    pub max_finished: Option<usize>,
+}
+
+
+/// Delete a task together with all of its descendants and their output.
+/// Only the root of a tree can be deleted, and only once nothing in the
+/// tree is queued, running or waiting on its children.
+pub async fn delete_tree(server: &Server, task_id: Uuid) -> Result<(), ServerError> {
+    if children(server).await.contains(&task_id) {
+        return Err(ServerError::InvalidTaskState(task_id));
+    }
+
+    delete_root(server, task_id).await
+}
+
+
+/// Every task that is the child of another.
+async fn children(server: &Server) -> HashSet<Uuid> {
+    let mut children = HashSet::new();
+    for task in server.tasks.lock().await.values() {
+        children.extend(task.lock().await.spec.children());
+    }
+
+    children
+}
+
+
+/// Delete the tree of a task known to be a root.
+async fn delete_root(server: &Server, task_id: Uuid) -> Result<(), ServerError> {
+    let tree = {
+        let mut tasks = server.tasks.lock().await;
+        if !tasks.contains_key(&task_id) {
+            return Err(ServerError::TaskNotFound(task_id));
+        }
+
+        let mut tree = vec![];
+        let mut pending = vec![task_id];
+        while let Some(id) = pending.pop() {
+            let Some(task) = tasks.get(&id) else {
+                continue;
+            };
+
+            let task = task.lock().await;
+            if matches!(task.status, TaskStatus::Queued | TaskStatus::Running | TaskStatus::Waiting) {
+                return Err(ServerError::TaskRunning(task_id));
+            }
+
+            pending.extend(task.spec.children());
+            tree.push(id);
+        }
+
+        for id in &tree {
+            tasks.remove(id);
+            server.unpersist_task(*id);
+        }
+
+        tree
+    };
+
+    // The tasks are gone from the map, so nothing writes their output
+    // while it is removed
+    if let Some(spool) = server.output.clone() {
+        let removed = tokio::task::spawn_blocking(move || {
+            for id in tree {
+                if let Err(err) = spool.remove(id) {
+                    eprintln!("Failed to delete output of task {}: {}", id, err);
+                }
+            }
+        });
+
+        let _ = removed.await;
+    }
+
+    if server.verbose {
+        eprintln!("Deleted task tree: {:?}", task_id);
+    }
+
+    Ok(())
+}
+
+
+/// Periodically delete finished task trees that are older than the
+/// retention TTL, then the oldest ones beyond the retention count.
+pub async fn collect(server: Arc<Server>) {
+    let retention = server.retention.clone();
+    if retention.ttl.is_none() && retention.max_finished.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(COLLECT_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let mut children = HashSet::new();
+        let mut finished = vec![];
+        for (id, task) in server.tasks.lock().await.iter() {
+            let task = task.lock().await;
+            children.extend(task.spec.children());
+            if let Some(finished_at) = task.finished_at {
+                finished.push((finished_at, *id));
+            }
+        }
+
+        // Newest first, so that the trees beyond the count are the oldest
+        finished.retain(|(_, id)| !children.contains(id));
+        finished.sort_by(|a, b| b.cmp(a));
+
+        let now = SystemTime::now();
+        for (n, (finished_at, id)) in finished.into_iter().enumerate() {
+            let age = now.duration_since(finished_at).unwrap_or_default();
+            let expired = retention.ttl.is_some_and(|ttl| age > ttl);
+            let excess = retention.max_finished.is_some_and(|max_finished| n >= max_finished);
+            if expired || excess {
+                // Trees that are running again are collected once they finish
+                let _ = delete_root(&server, id).await;
+            }
+        }
+    }
+}
@@ -1,34 +1,159 @@
-use axum::{extract::{Path, State}, response::IntoResponse, Json};
+use axum::{extract::{Path, Query, State}, response::IntoResponse, Json};
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
 use axum_streams::StreamBodyAs;
+use futures::{Stream, StreamExt};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 
-use crate::egg::server::{Server, ServerError, ServerPlan, ServerTask};
+use crate::egg::server::{collect, Server, ServerError, ServerPlan, ServerTask};
 use crate::error::Error;
+use crate::events::{Event, EventFilter};
 use crate::plans::{CreatePlan, Plan};
-use crate::process::OutputStream;
-use crate::tasks::{CreateTask, Task, TaskPlan, TaskStatus, TaskState};
+use crate::plans::diff::{self, PlanDiff};
+use crate::process::{OutputStream, Process, Rusage};
+use crate::tasks::{CreateTask, Task, TaskPlan, TaskState, TaskStatus};
+
+
+#[derive(Deserialize)]
+pub struct OutputQuery {
+    /// One-based attempt to stream, defaulting to the current attempt
+    pub attempt: Option<usize>,
+    /// Start from the entry with this sequence number
+    pub since: Option<usize>,
+    /// Start no earlier than this many entries before the current end
+    pub tail: Option<usize>,
+}
+
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    /// Version to compare from, defaulting to the one before `to`
+    pub from: Option<u64>,
+    /// Version to compare to, defaulting to the newest
+    pub to: Option<u64>,
+}
+
+
+#[derive(Deserialize)]
+pub struct PlanQuery {
+    /// Version to instantiate, defaulting to the newest
+    pub version: Option<u64>,
+}
+
+
+/// Filters and paging of `GET /plans`. Times are in seconds since the Unix
+/// epoch and bound the creation time.
+#[derive(Deserialize)]
+pub struct PlanListQuery {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Return at most this many plans
+    pub limit: Option<usize>,
+    /// Continue after the last plan of the previous page, with the cursor
+    /// of its `X-Next-Cursor` header
+    #[serde(default, deserialize_with = "cursor")]
+    pub after: Option<(SystemTime, Uuid)>,
+}
+
+
+/// Filters and paging of `GET /tasks`. Times are in seconds since the Unix
+/// epoch and bound the creation time.
+#[derive(Deserialize)]
+pub struct TaskListQuery {
+    pub status: Option<TaskStatus>,
+    pub plan: Option<Uuid>,
+    pub plan_version: Option<u64>,
+    /// Leave out tasks that are children of other tasks
+    #[serde(default)]
+    pub root_only: bool,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Return at most this many tasks
+    pub limit: Option<usize>,
+    /// Continue after the last task of the previous page, with the cursor
+    /// of its `X-Next-Cursor` header
+    #[serde(default, deserialize_with = "cursor")]
+    pub after: Option<(SystemTime, Uuid)>,
+}
+
+
+/// Header of a list response with the cursor of the next page, only sent
+/// when the page was cut short by its limit.
+const NEXT_CURSOR: &str = "x-next-cursor";
+
+
+/// Parse a paging cursor, written `<created_at>:<id>` with the creation time
+/// of the last item of the previous page in nanoseconds since the Unix epoch.
+/// The item itself need not exist any more.
+fn cursor<'de, D: Deserializer<'de>>(
+    deserializer: D
+) -> Result<Option<(SystemTime, Uuid)>, D::Error> {
+    let cursor = String::deserialize(deserializer)?;
+    let (created_at, id) = cursor.split_once(':')
+        .ok_or_else(|| D::Error::custom("expected <created_at>:<id>"))?;
+    let created_at: u64 = created_at.parse().map_err(D::Error::custom)?;
+    let id = id.parse().map_err(D::Error::custom)?;
+    Ok(Some((UNIX_EPOCH + Duration::from_nanos(created_at), id)))
+}
+
+
+fn format_cursor(created_at: SystemTime, id: Uuid) -> String {
+    let created_at = created_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}:{}", created_at.as_nanos(), id)
+}
+
+
+#[derive(Deserialize)]
+pub struct StdinQuery {
+    /// Close stdin once the body is written
+    #[serde(default)]
+    pub close: bool,
+}
+
+
+pub async fn cancel_task(
+    State(server): State<Arc<Server>>,
+    Path(task_id): Path<Uuid>
+) -> Result<Json<TaskState>, ServerError> {
+    Ok(Json(crate::egg::server::run::cancel_task(server, task_id).await?))
+}
 
 
 pub async fn create_plan(
     State(server): State<Arc<Server>>,
     body: Json<CreatePlan>
-) -> Json<Plan> {
-    let plan = Plan {
-        id: Uuid::new_v4(),
-        spec: body.spec.clone(),
-        version: 0,
-    };
+) -> Result<Json<Plan>, ServerError> {
+    if let Err(Error::InvalidPlan(reason)) = body.validate() {
+        return Err(ServerError::InvalidPlan(reason));
+    }
+
+    // Hold the plans while checking the name so that it stays unique
+    let mut plans = server.plans.lock().await;
+    if let Some(ref name) = body.name {
+        if find_plan(&plans, name, None).await.is_some() {
+            return Err(ServerError::PlanExists(name.clone()));
+        }
+    }
 
-    server.plans.lock().await.insert(
-        plan.id,
-        Arc::new(Mutex::new(ServerPlan {
-            versions: vec![plan.spec.clone()]
-        }))
-    );
+    let created_at = SystemTime::now();
+    let plan = body.to_plan(Uuid::new_v4(), 1, created_at);
+    let state = ServerPlan { versions: vec![body.0], created_at };
+    server.persist_plan(plan.id, &state);
+    server.publish(Event::PlanCreated(plan.clone()));
+    plans.insert(plan.id, Arc::new(Mutex::new(state)));
 
-    Json(plan)
+    Ok(Json(plan))
 }
 
 
@@ -36,26 +161,64 @@ pub async fn create_task(
     State(server): State<Arc<Server>>,
     body: Json<CreateTask>
 ) -> Json<Task> {
-    let task = Task {
-        id: Uuid::new_v4(),
-        plan: None,
-        spec: body.spec.clone(),
-        status: TaskStatus::Pending,
-    };
+    let id = Uuid::new_v4();
+    let task = ServerTask::new(None, body.spec.clone());
+    let response = task.task(id);
+    server.persist_task(id, &task);
+    server.tasks.lock().await.insert(id, Arc::new(Mutex::new(task)));
+    Json(response)
+}
+
 
-    server.tasks.lock().await.insert(
-        task.id,
-        Arc::new(Mutex::new(ServerTask {
-            plan: None,
-            spec: body.spec.clone(),
-            status: TaskStatus::Pending,
-            running: None,
-            finished: Arc::new(Notify::new()),
-            error: None,
-        }))
-    );
+pub async fn diff_plan(
+    State(server): State<Arc<Server>>,
+    Path(plan_id): Path<Uuid>,
+    Query(query): Query<DiffQuery>
+) -> Result<Json<PlanDiff>, ServerError> {
+    let (to, to_version, _) = plan_version(&server, plan_id, query.to).await?;
+    let from_version = query.from.unwrap_or(to_version.saturating_sub(1).max(1));
+    let (from, from_version, _) = plan_version(&server, plan_id, Some(from_version)).await?;
 
-    Json(task)
+    Ok(Json(PlanDiff {
+        from: from_version,
+        to: to_version,
+        changes: diff::diff(&from, &to),
+    }))
+}
+
+
+pub async fn events(
+    State(server): State<Arc<Server>>,
+    Query(filter): Query<EventFilter>
+) -> Sse<impl Stream<Item = Result<SseEvent, axum::Error>>> {
+    let receiver = server.events.subscribe();
+    let stream = futures::stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if filter.matches(&event) => {
+                        let event = SseEvent::default().json_data(&event);
+                        return Some((event, receiver));
+                    }
+                    Ok(_) => {}
+                    // Sent whatever the filter, since any of the missed
+                    // events may have matched it
+                    Err(RecvError::Lagged(missed)) => {
+                        let event = SseEvent::default()
+                            .event("lagged")
+                            .json_data(Event::Lagged { missed });
+                        return Some((event, receiver));
+                    }
+                    Err(RecvError::Closed) => {
+                        return None;
+                    }
+                }
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
 
@@ -63,25 +226,40 @@ pub async fn get_plan(
     State(server): State<Arc<Server>>,
     Path(plan_id): Path<Uuid>
 ) -> Result<Json<Plan>, ServerError> {
-    match server.plans.lock().await.get(&plan_id) {
-        Some(plan) => {
-            let state = plan.lock().await;
-            let version = state.versions.len();
-            match state.versions.last() {
-                Some(plan) => {
-                    return Ok(Json(Plan {
-                        id: plan_id,
-                        spec: plan.clone(),
-                        version: version as u64,
-                    }));
-                }
-                None => {
-                    return Err(ServerError::PlanNotFound(plan_id));
-                }
-            }
+    let (definition, version, created_at) = plan_version(&server, plan_id, None).await?;
+    Ok(Json(definition.to_plan(plan_id, version, created_at)))
+}
+
+
+pub async fn get_plan_version(
+    State(server): State<Arc<Server>>,
+    Path((plan_id, version)): Path<(Uuid, u64)>
+) -> Result<Json<Plan>, ServerError> {
+    let (definition, version, created_at) = plan_version(&server, plan_id, Some(version)).await?;
+    Ok(Json(definition.to_plan(plan_id, version, created_at)))
+}
+
+
+pub async fn list_plan_versions(
+    State(server): State<Arc<Server>>,
+    Path(plan_id): Path<Uuid>
+) -> Result<Json<Vec<Plan>>, ServerError> {
+    let plan = match server.plans.lock().await.get(&plan_id) {
+        Some(plan) => plan.clone(),
+        None => {
+            return Err(ServerError::PlanNotFound(plan_id));
         }
-        None => Err(ServerError::TaskNotFound(plan_id)),
-    }
+    };
+
+    let state = plan.lock().await;
+    let versions = state.versions.iter()
+        .enumerate()
+        .map(|(index, definition)| {
+            definition.to_plan(plan_id, index as u64 + 1, state.created_at)
+        })
+        .collect();
+
+    Ok(Json(versions))
 }
 
 
@@ -89,89 +267,194 @@ pub async fn get_task(
     State(server): State<Arc<Server>>,
     Path(task_id): Path<Uuid>
 ) -> Result<Json<Task>, ServerError> {
-    match server.tasks.lock().await.get(&task_id) {
-        Some(task) => {
-            let task = task.lock().await;
-            Ok(Json(Task {
-                id: task_id,
-                plan: task.plan.clone(),
-                spec: task.spec.clone(),
-                status: task.status.clone(),
-            }))
-        }
+    let tasks = server.tasks.lock().await;
+    match tasks.get(&task_id) {
+        Some(task) => Ok(Json(task_response(&tasks, task_id, &*task.lock().await).await)),
         None => Err(ServerError::TaskNotFound(task_id)),
     }
 }
 
 
-pub async fn list_plans(State(server): State<Arc<Server>>) -> Json<Vec<Plan>> {
-    let mut plans = vec![];
+pub async fn delete_task(
+    State(server): State<Arc<Server>>,
+    Path(task_id): Path<Uuid>
+) -> Result<StatusCode, ServerError> {
+    collect::delete_tree(&server, task_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
+
+pub async fn list_plans(
+    State(server): State<Arc<Server>>,
+    Query(query): Query<PlanListQuery>
+) -> (HeaderMap, Json<Vec<Plan>>) {
+    let mut items = vec![];
     for (id, plan) in server.plans.lock().await.iter() {
         let plan = plan.lock().await;
         let version = plan.versions.len() as u64;
-        match plan.versions.last() {
-            Some(plan) => {
-                plans.push(Plan {
-                    id: *id,
-                    spec: plan.clone(),
-                    version: version,
-                })
-            }
-            None => {},
+        if let Some(definition) = plan.versions.last() {
+            items.push((plan.created_at, *id, definition.to_plan(*id, version, plan.created_at)));
         }
     }
 
-    Json(plans)
+    page(items, query.since, query.until, query.after, query.limit)
 }
 
 
-pub async fn list_tasks(State(server): State<Arc<Server>>) -> Json<Vec<Task>> {
-    let mut tasks = vec![];
+pub async fn list_tasks(
+    State(server): State<Arc<Server>>,
+    Query(query): Query<TaskListQuery>
+) -> (HeaderMap, Json<Vec<Task>>) {
+    let tasks = server.tasks.lock().await;
+
+    let mut children = HashSet::new();
+    if query.root_only {
+        for task in tasks.values() {
+            children.extend(task.lock().await.spec.children());
+        }
+    }
 
-    for (id, task) in server.tasks.lock().await.iter() {
+    let mut items = vec![];
+    for (id, task) in tasks.iter() {
         let task = task.lock().await;
-        tasks.push(Task {
-            id: *id,
-            plan: task.plan.clone(),
-            spec: task.spec.clone(),
-            status: task.status.clone(),
-        });
+        let plan = task.plan.as_ref();
+        if query.status.as_ref().is_some_and(|status| *status != task.status)
+            || query.plan.is_some_and(|id| plan.is_none_or(|plan| plan.id != id))
+            || query.plan_version.is_some_and(|version| {
+                plan.is_none_or(|plan| plan.version != version)
+            })
+            || children.contains(id)
+        {
+            continue;
+        }
+
+        items.push((task.created_at, *id, task_response(&tasks, *id, &task).await));
     }
 
-    Json(tasks)
+    page(items, query.since, query.until, query.after, query.limit)
 }
 
 
-pub async fn plan(
-    State(server): State<Arc<Server>>,
-    Path(plan_id): Path<Uuid>
-) -> Result<Json<Task>, ServerError> {
-    let (spec, version) = match server.plans.lock().await.get(&plan_id) {
-        Some(plan) => {
-            let state = plan.lock().await;
-            let version = state.versions.len() as u64;
-            match state.versions.last() {
-                Some(plan) => (plan.clone(), version),
-                None => {
-                    return Err(ServerError::PlanNotFound(plan_id));
-                }
+/// A task as returned by the API, with the resource usage of a parent
+/// summed over its descendants.
+async fn task_response(
+    tasks: &HashMap<Uuid, Arc<Mutex<ServerTask>>>,
+    id: Uuid,
+    task: &ServerTask
+) -> Task {
+    let mut response = task.task(id);
+    let children = task.spec.children();
+    if !children.is_empty() {
+        response.rusage = total_rusage(tasks, children).await;
+    }
+
+    response
+}
+
+
+fn total_rusage(
+    tasks: &HashMap<Uuid, Arc<Mutex<ServerTask>>>,
+    ids: Vec<Uuid>
+) -> Pin<Box<dyn Future<Output = Option<Rusage>> + Send + '_>> {
+    Box::pin(async move {
+        let mut total: Option<Rusage> = None;
+        for id in ids {
+            let Some(task) = tasks.get(&id) else {
+                continue;
+            };
+
+            let (rusage, children) = {
+                let task = task.lock().await;
+                (task.rusage.clone(), task.spec.children())
+            };
+
+            let rusage = if children.is_empty() {
+                rusage
+            } else {
+                total_rusage(tasks, children).await
+            };
+
+            if let Some(rusage) = rusage {
+                total.get_or_insert_with(Rusage::default).add(&rusage);
             }
         }
-        None => {
-            return Err(ServerError::PlanNotFound(plan_id));
+
+        total
+    })
+}
+
+
+/// Sort items by creation time, then keep those created in the time range
+/// and after the cursor, up to the limit. Ties are broken by id so that
+/// the order is stable across pages. Pages cut short by the limit come with
+/// the cursor of the next one.
+fn page<T>(
+    mut items: Vec<(SystemTime, Uuid, T)>,
+    since: Option<u64>,
+    until: Option<u64>,
+    after: Option<(SystemTime, Uuid)>,
+    limit: Option<usize>
+) -> (HeaderMap, Json<Vec<T>>) {
+    let since = since.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    let until = until.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+    items.sort_by_key(|(created_at, id, _)| (*created_at, *id));
+    items.retain(|(created_at, id, _)| {
+        since.is_none_or(|since| *created_at >= since)
+            && until.is_none_or(|until| *created_at < until)
+            && after.is_none_or(|after| (*created_at, *id) > after)
+    });
+
+    let mut headers = HeaderMap::new();
+    if let Some(limit) = limit.filter(|limit| items.len() > *limit) {
+        items.truncate(limit);
+        if let Some((created_at, id, _)) = items.last() {
+            let cursor = HeaderValue::try_from(format_cursor(*created_at, *id))
+                .expect("cursor is a valid header value");
+            headers.insert(NEXT_CURSOR, cursor);
         }
+    }
+
+    (headers, Json(items.into_iter().map(|(_, _, item)| item).collect()))
+}
+
+
+pub async fn plan(
+    State(server): State<Arc<Server>>,
+    Path(plan_id): Path<Uuid>,
+    Query(query): Query<PlanQuery>,
+    body: Bytes
+) -> Result<Json<Task>, ServerError> {
+    // Parameter values are optional, so an empty body is allowed
+    let params: BTreeMap<String, Value> = if body.is_empty() {
+        BTreeMap::new()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|err| ServerError::InvalidParams(err.to_string()))?
     };
 
-    let plan = TaskPlan { id: plan_id, version: version };
-    match crate::egg::server::plan::task(server, plan, spec).await {
+    let (definition, version, _) = plan_version(&server, plan_id, query.version).await?;
+    let plan = TaskPlan { id: plan_id, version };
+    match crate::egg::server::plan::instantiate(server, plan, definition, params).await {
         Ok(task) => Ok(Json(task)),
         Err(Error::PlanNotFound(id)) => Err(ServerError::PlanNotFound(id)),
+        Err(Error::InvalidPlan(reason)) => Err(ServerError::InvalidPlan(reason)),
+        Err(Error::InvalidParams(reason)) => Err(ServerError::InvalidParams(reason)),
         Err(_) => Err(ServerError::InternalServerError),
     }
 }
 
 
+/// Publish an earlier version of a plan again as its newest version.
+pub async fn rollback_plan(
+    State(server): State<Arc<Server>>,
+    Path((plan_id, version)): Path<(Uuid, u64)>
+) -> Result<Json<Plan>, ServerError> {
+    let (definition, _, _) = plan_version(&server, plan_id, Some(version)).await?;
+    update_plan(State(server), Path(plan_id), Json(definition)).await
+}
+
+
 pub async fn start_task(
     State(server): State<Arc<Server>>,
     Path(task_id): Path<Uuid>
@@ -182,23 +465,71 @@ pub async fn start_task(
 
 pub async fn task_output_stream(
     State(server): State<Arc<Server>>,
-    Path(task_id): Path<Uuid>
+    Path(task_id): Path<Uuid>,
+    Query(query): Query<OutputQuery>
 ) -> Result<impl IntoResponse, ServerError> {
-    let cmd = match server.tasks.lock().await.get(&task_id) {
-        Some(task) => {
-            match task.lock().await.running {
-                Some(ref cmd) => cmd.clone(),
-                None => {
+    let task = match server.tasks.lock().await.get(&task_id) {
+        Some(task) => task.clone(),
+        None => {
+            return Err(ServerError::TaskNotFound(task_id));
+        }
+    };
+
+    let task = task.lock().await;
+    let cmd = match (task.process(query.attempt), &server.output) {
+        (Some(cmd), _) => cmd,
+        // Output of tasks from before a restart can only be replayed
+        (None, Some(spool)) => {
+            let attempt = query.attempt.unwrap_or(task.attempts.len() + 1);
+            let path = spool.path(task_id, attempt);
+            match Process::replay(path).await {
+                Ok(cmd) => Arc::new(cmd),
+                Err(_) => {
                     return Err(ServerError::TaskNotFound(task_id));
                 }
             }
         }
+        (None, None) => {
+            return Err(ServerError::TaskNotFound(task_id));
+        }
+    };
+
+    let mut start = query.since.unwrap_or(0);
+    if let Some(tail) = query.tail {
+        start = start.max(cmd.output_len().await.saturating_sub(tail));
+    }
+
+    Ok(StreamBodyAs::json_nl(OutputStream::new(cmd, start)))
+}
+
+
+pub async fn task_stdin(
+    State(server): State<Arc<Server>>,
+    Path(task_id): Path<Uuid>,
+    Query(query): Query<StdinQuery>,
+    body: Body
+) -> Result<StatusCode, ServerError> {
+    let cmd = match server.tasks.lock().await.get(&task_id) {
+        Some(task) => task.lock().await.running.clone(),
         None => {
             return Err(ServerError::TaskNotFound(task_id));
         }
     };
 
-    Ok(StreamBodyAs::json_nl(OutputStream::new(cmd.clone())))
+    let cmd = cmd.ok_or(ServerError::InvalidTaskState(task_id))?;
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|_| ServerError::InternalServerError)?;
+        cmd.write_stdin(&chunk, false).await
+            .map_err(|_| ServerError::InvalidTaskState(task_id))?;
+    }
+
+    if query.close {
+        cmd.write_stdin(&[], true).await
+            .map_err(|_| ServerError::InvalidTaskState(task_id))?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
 
@@ -207,13 +538,128 @@ pub async fn update_plan(
     Path(plan_id): Path<Uuid>,
     body: Json<CreatePlan>
 ) -> Result<Json<Plan>, ServerError> {
+    if let Err(Error::InvalidPlan(reason)) = body.validate() {
+        return Err(ServerError::InvalidPlan(reason));
+    }
+
     let plans = server.plans.lock().await;
+    if let Some(ref name) = body.name {
+        if find_plan(&plans, name, Some(plan_id)).await.is_some() {
+            return Err(ServerError::PlanExists(name.clone()));
+        }
+    }
+
     let plan = plans.get(&plan_id).ok_or(ServerError::PlanNotFound(plan_id))?;
     let mut plan = plan.lock().await;
-    plan.versions.push(body.spec.clone());
-    Ok(Json(Plan {
-        id: plan_id,
-        spec: body.spec.clone(),
-        version: plan.versions.len() as u64,
-    }))
+    plan.versions.push(body.0.clone());
+    server.persist_plan(plan_id, &plan);
+
+    let plan = body.to_plan(plan_id, plan.versions.len() as u64, plan.created_at);
+    server.publish(Event::PlanUpdated(plan.clone()));
+    Ok(Json(plan))
+}
+
+
+/// Delete a plan with all of its versions. Tasks made from the plan are
+/// kept until they are deleted on their own.
+pub async fn delete_plan(
+    State(server): State<Arc<Server>>,
+    Path(plan_id): Path<Uuid>
+) -> Result<StatusCode, ServerError> {
+    if server.plans.lock().await.remove(&plan_id).is_none() {
+        return Err(ServerError::PlanNotFound(plan_id));
+    }
+
+    server.unpersist_plan(plan_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+
+pub async fn get_plan_by_name(
+    State(server): State<Arc<Server>>,
+    Path(name): Path<String>
+) -> Result<Json<Plan>, ServerError> {
+    let plan_id = plan_id_by_name(&server, &name).await?;
+    get_plan(State(server), Path(plan_id)).await
+}
+
+
+pub async fn plan_by_name(
+    State(server): State<Arc<Server>>,
+    Path(name): Path<String>,
+    query: Query<PlanQuery>,
+    body: Bytes
+) -> Result<Json<Task>, ServerError> {
+    let plan_id = plan_id_by_name(&server, &name).await?;
+    plan(State(server), Path(plan_id), query, body).await
+}
+
+
+pub async fn update_plan_by_name(
+    State(server): State<Arc<Server>>,
+    Path(name): Path<String>,
+    body: Json<CreatePlan>
+) -> Result<Json<Plan>, ServerError> {
+    let plan_id = plan_id_by_name(&server, &name).await?;
+    update_plan(State(server), Path(plan_id), body).await
+}
+
+
+pub async fn delete_plan_by_name(
+    State(server): State<Arc<Server>>,
+    Path(name): Path<String>
+) -> Result<StatusCode, ServerError> {
+    let plan_id = plan_id_by_name(&server, &name).await?;
+    delete_plan(State(server), Path(plan_id)).await
+}
+
+
+/// Look up a version of a plan, numbered from one, or its newest version.
+async fn plan_version(
+    server: &Server,
+    plan_id: Uuid,
+    version: Option<u64>
+) -> Result<(CreatePlan, u64, SystemTime), ServerError> {
+    let plan = match server.plans.lock().await.get(&plan_id) {
+        Some(plan) => plan.clone(),
+        None => {
+            return Err(ServerError::PlanNotFound(plan_id));
+        }
+    };
+
+    let state = plan.lock().await;
+    let version = version.unwrap_or(state.versions.len() as u64);
+    match version.checked_sub(1).and_then(|index| state.versions.get(index as usize)) {
+        Some(definition) => Ok((definition.clone(), version, state.created_at)),
+        None => Err(ServerError::PlanVersionNotFound(plan_id, version)),
+    }
+}
+
+
+async fn plan_id_by_name(server: &Server, name: &str) -> Result<Uuid, ServerError> {
+    let plans = server.plans.lock().await;
+    find_plan(&plans, name, None).await
+        .ok_or_else(|| ServerError::PlanNameNotFound(name.to_string()))
+}
+
+
+/// Find the plan whose latest version has this name, other than `except`.
+async fn find_plan(
+    plans: &HashMap<Uuid, Arc<Mutex<ServerPlan>>>,
+    name: &str,
+    except: Option<Uuid>
+) -> Option<Uuid> {
+    for (id, plan) in plans.iter() {
+        if Some(*id) == except {
+            continue;
+        }
+
+        let plan = plan.lock().await;
+        if plan.versions.last().and_then(|plan| plan.name.as_deref()) == Some(name) {
+            return Some(*id);
+        }
+    }
+
+    None
 }
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use crate::egg::server::Server;
+use crate::tasks::TaskStatus;
+
+
+/// How often the retention limits are enforced.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+
+/// Location and retention limits of the per-task output logs.
+#[derive(Clone, Debug)]
+pub struct OutputSpool {
+    pub dir: PathBuf,
+    pub max_age: Option<Duration>,
+    pub max_size: Option<u64>,
+}
+
+impl OutputSpool {
+    pub fn path(&self, task_id: Uuid, attempt: usize) -> PathBuf {
+        self.dir.join(task_id.to_string()).join(format!("{}.jsonl", attempt))
+    }
+
+    /// Delete every log of a task.
+    pub fn remove(&self, task_id: Uuid) -> std::io::Result<()> {
+        match std::fs::remove_dir_all(self.dir.join(task_id.to_string())) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Delete logs older than the maximum age, then the oldest logs until
+    /// the total size is within the limit. Logs in `active` are kept.
+    pub fn sweep(&self, active: &HashSet<PathBuf>) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        let mut logs = vec![];
+
+        for task_dir in std::fs::read_dir(&self.dir)? {
+            let task_dir = task_dir?.path();
+            if !task_dir.is_dir() {
+                continue;
+            }
+
+            for log in std::fs::read_dir(&task_dir)? {
+                let log = log?;
+                let path = log.path();
+                if active.contains(&path) {
+                    continue;
+                }
+
+                let metadata = log.metadata()?;
+                let modified = metadata.modified()?;
+                let age = now.duration_since(modified).unwrap_or_default();
+                if self.max_age.is_some_and(|max_age| age > max_age) {
+                    std::fs::remove_file(&path)?;
+                } else {
+                    logs.push((modified, metadata.len(), path));
+                }
+            }
+
+            // Only succeeds once the directory is empty
+            let _ = std::fs::remove_dir(&task_dir);
+        }
+
+        if let Some(max_size) = self.max_size {
+            logs.sort();
+            let mut total: u64 = logs.iter().map(|(_, size, _)| size).sum();
+            for (_, size, path) in logs {
+                if total <= max_size {
+                    break;
+                }
+
+                std::fs::remove_file(&path)?;
+                total -= size;
+                if let Some(task_dir) = path.parent() {
+                    let _ = std::fs::remove_dir(task_dir);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Periodically enforce the retention limits of the server's output spool.
+pub async fn retain(server: Arc<Server>) {
+    let spool = match server.output {
+        Some(ref spool) if spool.max_age.is_some() || spool.max_size.is_some() => {
+            spool.clone()
+        }
+        _ => {
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        // Never delete the log of a command that is still running
+        let mut active = HashSet::new();
+        for (id, task) in server.tasks.lock().await.iter() {
+            let task = task.lock().await;
+            if task.status == TaskStatus::Running {
+                active.insert(spool.path(*id, task.attempts.len() + 1));
+            }
+        }
+
+        let spool = spool.clone();
+        let result = tokio::task::spawn_blocking(move || spool.sweep(&active)).await;
+        if let Ok(Err(err)) = result {
+            eprintln!("Failed to enforce output retention: {}", err);
+        }
+    }
+}
@@ -1,100 +1,126 @@
 use futures::Future;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use std::time::Duration;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use crate::error::Error;
-use crate::plans::PlanSpec;
+use crate::plans::{matrix_combinations, CreatePlan, PlanSpec};
 use crate::egg::server::{Server, ServerTask};
-use crate::tasks::{Task, TaskPlan, TaskSpec, TaskStatus};
+use crate::tasks::{Task, TaskPlan, TaskSpec, TaskStep};
+
+
+pub async fn instantiate(
+    server: Arc<Server>,
+    plan: TaskPlan,
+    definition: CreatePlan,
+    params: BTreeMap<String, Value>,
+) -> Result<Task, Error> {
+    let params = definition.resolve_params(params)?;
+    let spec = definition.spec.clone().substitute("params", &params)?;
+    let root = task(server.clone(), plan, None, spec).await?;
+
+    let task = match server.tasks.lock().await.get(&root.id) {
+        Some(task) => task.clone(),
+        None => {
+            return Err(Error::TaskNotFound(root.id));
+        }
+    };
+
+    let mut task = task.lock().await;
+    task.params = params;
+
+    // The plan-wide deadline bounds the root task with all of its retries
+    task.deadline = definition.deadline.map(Duration::from_secs);
+
+    server.persist_task(root.id, &task);
+    Ok(task.task(root.id))
+}
 
 
 pub fn task(
     server: Arc<Server>,
     plan: TaskPlan,
+    name: Option<String>,
     spec: PlanSpec,
 ) -> Pin<Box<dyn Future<Output = Result<Task, Error>> + Send>> {
     Box::pin(async move {
-        match spec {
-            PlanSpec::Command { args } => {
-                let task = Task {
-                    id: Uuid::new_v4(),
-                    plan: Some(plan.clone()),
-                    spec: TaskSpec::Command { args },
-                    status: TaskStatus::Pending,
-                };
-
-                server.tasks.lock().await.insert(
-                    task.id,
-                    Arc::new(Mutex::new(ServerTask {
-                        plan: Some(plan.clone()),
-                        spec: task.spec.clone(),
-                        status: TaskStatus::Pending,
-                        running: None,
-                        finished: Arc::new(Notify::new()),
-                        error: None,
-                    }))
-                );
-
-                Ok(task)
+        let spec = match spec {
+            PlanSpec::Command { args, env, cwd, clear_env, stdin, timeout, retry } => {
+                TaskSpec::Command { args, env, cwd, clear_env, stdin, timeout, retry }
+            }
+            PlanSpec::Script { script, shell, env, cwd, clear_env, stdin, timeout, retry } => {
+                TaskSpec::Script { script, shell, env, cwd, clear_env, stdin, timeout, retry }
             }
-            PlanSpec::TaskGroup { parallel } => {
+            PlanSpec::TaskGroup { parallel, max_parallel, fail_fast, continue_on_error, retry } => {
                 let mut tasks = Vec::new();
                 for child_spec in parallel {
-                    let child = task(server.clone(), plan.clone(), child_spec).await?;
+                    let child = task(server.clone(), plan.clone(), None, child_spec).await?;
                     tasks.push(child.id);
                 }
 
-                let task = Task {
-                    id: Uuid::new_v4(),
-                    plan: Some(plan.clone()),
-                    spec: TaskSpec::TaskGroup { parallel: tasks },
-                    status: TaskStatus::Pending,
-                };
-
-                server.tasks.lock().await.insert(
-                    task.id,
-                    Arc::new(Mutex::new(ServerTask {
-                        plan: Some(plan.clone()),
-                        spec: task.spec.clone(),
-                        status: TaskStatus::Pending,
-                        running: None,
-                        finished: Arc::new(Notify::new()),
-                        error: None,
-                    }))
-                );
-
-                Ok(task)
+                TaskSpec::TaskGroup {
+                    parallel: tasks,
+                    max_parallel,
+                    fail_fast,
+                    continue_on_error,
+                    retry,
+                }
             }
-            PlanSpec::TaskList { serial } => {
+            PlanSpec::TaskList { serial, fail_fast, continue_on_error, retry } => {
                 let mut tasks = Vec::new();
                 for child_spec in serial {
-                    let child = task(server.clone(), plan.clone(), child_spec).await?;
+                    let child = task(server.clone(), plan.clone(), None, child_spec).await?;
                     tasks.push(child.id);
                 }
 
-                let task = Task {
-                    id: Uuid::new_v4(),
-                    plan: Some(plan.clone()),
-                    spec: TaskSpec::TaskList { serial: tasks },
-                    status: TaskStatus::Pending,
-                };
+                TaskSpec::TaskList { serial: tasks, fail_fast, continue_on_error, retry }
+            }
+            PlanSpec::Steps { steps, retry } => {
+                let mut tasks = Vec::new();
+                for step in steps {
+                    let name = Some(step.name.clone());
+                    let child = task(server.clone(), plan.clone(), name, step.spec).await?;
+                    tasks.push(TaskStep { name: step.name, needs: step.needs, task: child.id });
+                }
 
-                server.tasks.lock().await.insert(
-                    task.id,
-                    Arc::new(Mutex::new(ServerTask {
-                        plan: Some(plan.clone()),
-                        spec: task.spec.clone(),
-                        status: TaskStatus::Pending,
-                        running: None,
-                        finished: Arc::new(Notify::new()),
-                        error: None,
-                    }))
-                );
+                TaskSpec::Steps { steps: tasks, retry }
+            }
+            PlanSpec::Matrix { matrix, run, max_parallel, fail_fast, continue_on_error, retry } => {
+                let mut tasks = Vec::new();
+                for values in matrix_combinations(&matrix) {
+                    let child_spec = run.as_ref().clone().substitute("matrix", &values)?;
+                    let name = values.iter()
+                        .map(|(key, value)| match value {
+                            Value::String(value) => format!("{}={}", key, value),
+                            value => format!("{}={}", key, value),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
 
-                Ok(task)
+                    let child = task(server.clone(), plan.clone(), Some(name), child_spec).await?;
+                    tasks.push(child.id);
+                }
+
+                TaskSpec::TaskGroup {
+                    parallel: tasks,
+                    max_parallel,
+                    fail_fast,
+                    continue_on_error,
+                    retry,
+                }
             }
-        }
+        };
+
+        let id = Uuid::new_v4();
+        let mut task = ServerTask::new(Some(plan), spec);
+        task.name = name;
+        let response = task.task(id);
+        server.persist_task(id, &task);
+        server.tasks.lock().await.insert(id, Arc::new(Mutex::new(task)));
+        Ok(response)
     })
 }
@@ -1,11 +1,17 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashSet;
 use std::future::Future;
+use std::os::unix::process::ExitStatusExt;
 use std::pin::Pin;
+use std::process::ExitStatus;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, Semaphore};
 use uuid::Uuid;
 
-use crate::egg::server::{Server, ServerError};
+use crate::egg::server::{Server, ServerError, ServerTask};
 use crate::error::Error;
-use crate::process::Process;
+use crate::process::{Process, Rusage};
 use crate::tasks::{TaskSpec, TaskStatus, TaskState};
 
 
@@ -20,7 +26,8 @@ pub fn start_task(
 
         let task = match server.tasks.lock().await.get(&task_id) {
             Some(task) => {
-                if task.lock().await.status != TaskStatus::Pending {
+                // Children of a bounded group are queued before they start
+                if !matches!(task.lock().await.status, TaskStatus::Pending | TaskStatus::Queued) {
                     return Err(ServerError::InvalidTaskState(task_id));
                 }
 
@@ -32,8 +39,12 @@ pub fn start_task(
         };
 
         let mut task = task.lock().await;
+        let previous = task.status.clone();
         match task.spec {
-            TaskSpec::Command { .. } => {
+            TaskSpec::Command { .. } | TaskSpec::Script { .. } if server.slots.is_some() => {
+                task.status = TaskStatus::Queued;
+            }
+            TaskSpec::Command { .. } | TaskSpec::Script { .. } => {
                 task.status = TaskStatus::Running;
             }
             TaskSpec::TaskGroup { .. } => {
@@ -42,6 +53,18 @@ pub fn start_task(
             TaskSpec::TaskList { .. } => {
                 task.status = TaskStatus::Waiting;
             }
+            TaskSpec::Steps { .. } => {
+                task.status = TaskStatus::Waiting;
+            }
+        }
+
+        if task.status != TaskStatus::Queued {
+            task.started_at.get_or_insert_with(SystemTime::now);
+        }
+
+        server.persist_task(task_id, &task);
+        if task.status != previous {
+            server.publish_status(task_id, &task);
         }
 
         tokio::spawn(async move {
@@ -57,6 +80,87 @@ pub fn start_task(
 }
 
 
+pub async fn cancel_task(
+    server: Arc<Server>,
+    task_id: Uuid
+) -> Result<TaskState, ServerError> {
+    let task = match server.tasks.lock().await.get(&task_id) {
+        Some(task) => task.clone(),
+        None => {
+            return Err(ServerError::TaskNotFound(task_id));
+        }
+    };
+
+    if task.lock().await.status.is_finished() {
+        return Err(ServerError::InvalidTaskState(task_id));
+    }
+
+    terminate_task(server, task_id, TaskStatus::Cancelled).await;
+
+    let task = task.lock().await;
+    Ok(TaskState {
+        id: task_id,
+        spec: task.spec.clone(),
+        status: task.status.clone(),
+    })
+}
+
+
+/// Move a task and all of its unfinished descendants to the given terminal
+/// status, killing any running processes. Pending tasks are never started.
+pub fn terminate_task(
+    server: Arc<Server>,
+    task_id: Uuid,
+    status: TaskStatus
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        let task = match server.tasks.lock().await.get(&task_id) {
+            Some(task) => task.clone(),
+            None => {
+                return;
+            }
+        };
+
+        let (spec, running) = {
+            let mut task = task.lock().await;
+            if task.status.is_finished() {
+                return;
+            }
+
+            if server.verbose {
+                eprintln!("Terminating task {}: {:?}", task_id, status);
+            }
+
+            task.error = Some(match status {
+                TaskStatus::TimedOut => Error::TimedOut(task_id),
+                _ => Error::Cancelled(task_id),
+            });
+            task.status = status.clone();
+            task.finished_at = Some(SystemTime::now());
+            task.finished.notify_waiters();
+            server.persist_task(task_id, &task);
+            server.publish_status(task_id, &task);
+            (task.spec.clone(), task.running.clone())
+        };
+
+        match spec {
+            TaskSpec::Command { .. } | TaskSpec::Script { .. } => {
+                if let Some(process) = running {
+                    process.terminate(server.kill_grace).await;
+                }
+            }
+            TaskSpec::TaskGroup { .. } |
+            TaskSpec::TaskList { .. } |
+            TaskSpec::Steps { .. } => {
+                futures::future::join_all(spec.children().into_iter().map(|child_id| {
+                    terminate_task(server.clone(), child_id, status.clone())
+                })).await;
+            }
+        }
+    })
+}
+
+
 async fn run_task(
     server: Arc<Server>,
     task_id: Uuid
@@ -68,106 +172,414 @@ async fn run_task(
         }
     };
 
+    let deadline = task.lock().await.deadline;
+    let run = run_attempts(server.clone(), task_id, task);
+    let result = match deadline {
+        Some(deadline) => match tokio::time::timeout(deadline, run).await {
+            Ok(result) => result,
+            Err(_) => {
+                terminate_task(server, task_id, TaskStatus::TimedOut).await;
+                return;
+            }
+        },
+        None => run.await,
+    };
+
+    match result {
+        Ok(_) => {
+            finish_task(server, task_id).await;
+        }
+        Err(Error::TimedOut(id)) if id == task_id => {
+            terminate_task(server, task_id, TaskStatus::TimedOut).await;
+        }
+        Err(err) => {
+            fail_task(server, task_id, err).await;
+        }
+    }
+}
+
+
+async fn run_attempts(
+    server: Arc<Server>,
+    task_id: Uuid,
+    task: Arc<Mutex<ServerTask>>
+) -> Result<(), Error> {
     let spec = task.lock().await.spec.clone();
-    match spec {
-        TaskSpec::Command { ref args } => {
+    let mut attempt = 1;
+
+    loop {
+        let err = match run_spec(server.clone(), task_id, task.clone(), spec.clone()).await {
+            Ok(_) => {
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        let policy = match spec.retry() {
+            Some(policy) if attempt < policy.attempts => policy,
+            _ => {
+                return Err(err);
+            }
+        };
+
+        // Timeouts have no exit code and are always retried
+        let timed_out = matches!(err, Error::TimedOut(id) if id == task_id);
+        if !timed_out && !policy.on_exit_codes.is_empty() {
+            match failure_exit_code(server.clone(), err.clone()).await {
+                Some(code) if policy.on_exit_codes.contains(&code) => {}
+                _ => {
+                    return Err(err);
+                }
+            }
+        }
+
+        if server.verbose {
+            eprintln!("Retrying task {} after attempt {}: {:?}", task_id, attempt, err);
+        }
+
+        let backoff = Duration::from_secs(policy.backoff)
+            .saturating_mul(2u32.saturating_pow(attempt - 1));
+        tokio::time::sleep(backoff).await;
+
+        // The task may have been cancelled while backing off
+        {
             let mut task = task.lock().await;
-            let cmd = Arc::new(Process::new());
-            let cmd_clone = cmd.clone();
-            let args = args.clone();
-            task.running = Some(cmd);
-            tokio::spawn(async move {
-                match cmd_clone.run(&args, server.verbose).await {
-                    Ok(_) => {
-                        finish_task(server, task_id).await;
+            if task.status.is_finished() {
+                return Err(err);
+            }
+
+            task.reset(if timed_out { TaskStatus::TimedOut } else { TaskStatus::Failure });
+            server.persist_task(task_id, &task);
+        }
+
+        for child_id in spec.children() {
+            reset_task(server.clone(), child_id).await;
+        }
+
+        attempt += 1;
+    }
+}
+
+
+async fn run_spec(
+    server: Arc<Server>,
+    task_id: Uuid,
+    task: Arc<Mutex<ServerTask>>,
+    spec: TaskSpec
+) -> Result<(), Error> {
+    match spec {
+        TaskSpec::Command { .. } | TaskSpec::Script { .. } => {
+            let command = spec.command().ok_or(Error::TaskFailed(task_id))?;
+
+            // Wait for a slot in the server's pool, holding it until the
+            // command exits
+            let permit = match server.slots {
+                Some(ref slots) => {
+                    queue_task(server.clone(), task_id).await;
+                    let permit = slots.clone().acquire_owned().await
+                        .map_err(|_| Error::TaskFailed(task_id))?;
+                    Some(permit)
+                }
+                None => None,
+            };
+
+            let cmd = {
+                let mut task = task.lock().await;
+                match task.status {
+                    TaskStatus::Running => {}
+                    TaskStatus::Queued => {
+                        task.status = TaskStatus::Running;
+                        task.started_at.get_or_insert_with(SystemTime::now);
+                        server.persist_task(task_id, &task);
+                        server.publish_status(task_id, &task);
                     }
-                    Err(err) => {
-                        fail_task(server, task_id, err).await;
+                    _ => {
+                        return Err(Error::TaskFailed(task_id));
                     }
                 }
+
+                let cmd = match server.output {
+                    Some(ref spool) => {
+                        let path = spool.path(task_id, task.attempts.len() + 1);
+                        Arc::new(Process::spooled(path)?)
+                    }
+                    None => Arc::new(Process::new()),
+                };
+
+                task.running = Some(cmd.clone());
+                cmd
+            };
+
+            // Run the process in its own task so that its exit status is
+            // still recorded if this task is abandoned at the deadline
+            let process = cmd.clone();
+            let runner = server.clone();
+            let mut handle = tokio::spawn(async move {
+                let result = cmd.clone().run(&command, runner.verbose).await;
+                drop(permit);
+                if let Some(status) = cmd.status().await {
+                    record_exit_status(runner, task_id, status, cmd.rusage().await).await;
+                }
+
+                result
             });
+
+            // The timeout bounds each attempt, so that a timed out attempt
+            // can be retried
+            let timeout = match spec.timeout() {
+                Some(timeout) => Duration::from_secs(timeout),
+                None => {
+                    return handle.await.unwrap_or(Err(Error::TaskFailed(task_id)));
+                }
+            };
+
+            match tokio::time::timeout(timeout, &mut handle).await {
+                Ok(result) => result.unwrap_or(Err(Error::TaskFailed(task_id))),
+                Err(_) => {
+                    if server.verbose {
+                        eprintln!("Timed out task: {:?}", task_id);
+                    }
+
+                    // Wait for the exit status to be recorded so that a
+                    // retry does not mistake it for its own
+                    process.terminate(server.kill_grace).await;
+                    let _ = handle.await;
+                    Err(Error::TimedOut(task_id))
+                }
+            }
         }
-        TaskSpec::TaskGroup { parallel } => {
-            let mut handles = vec![];
+        TaskSpec::TaskGroup { parallel, max_parallel, fail_fast, continue_on_error, .. } => {
+            let limit = max_parallel.map(|limit| Arc::new(Semaphore::new(limit.max(1))));
+            let mut handles = FuturesUnordered::new();
 
-            for child_id in parallel {
+            for child_id in parallel.iter().copied() {
                 let server = server.clone();
-                let handle = tokio::spawn(async move {
-                    start_task(server.clone(), child_id).await
-                        .map_err(|err| {
-                            match err {
-                                ServerError::TaskNotFound(_) => {
-                                    Error::TaskNotFound(child_id)
-                                }
-                                _ => {
-                                    Error::TaskFailed(child_id)
-                                }
-                            }
-                        })?;
-
-                    // Wait for the child task to finish
-                    if let Err(_) = wait_task(server.clone(), child_id).await {
-                        Err(Error::TaskFailed(child_id))
-                    } else {
-                        Ok(())
+                let handle = match limit {
+                    Some(ref limit) => {
+                        queue_task(server.clone(), child_id).await;
+                        let limit = limit.clone();
+                        tokio::spawn(async move {
+                            let _permit = limit.acquire_owned().await
+                                .map_err(|_| Error::TaskFailed(child_id))?;
+                            run_child(server, child_id).await
+                        })
                     }
-                });
+                    None => tokio::spawn(run_child(server, child_id)),
+                };
                 handles.push(handle);
             }
 
-            for handle in handles {
-                if let Err(_) = handle.await {
-                    fail_task(server.clone(), task_id, Error::TaskFailed(task_id)).await;
-                    return;
+            // Wait for every child, then fail with the first failure
+            let mut failure = None;
+            while let Some(handle) = handles.next().await {
+                let result = match handle {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::TaskFailed(task_id)),
+                };
+
+                if let Err(err) = result {
+                    if failure.is_none() && fail_fast {
+                        cancel_children(server.clone(), &parallel).await;
+                    }
+
+                    failure.get_or_insert(err);
                 }
             }
 
-            finish_task(server, task_id).await;
+            match failure {
+                Some(err) if !continue_on_error => Err(err),
+                _ => Ok(()),
+            }
         }
-        TaskSpec::TaskList { ref serial } => {
-            for child_id in serial {
-                // Start the child task
-                if let Err(err) = start_task(server.clone(), *child_id).await {
-                    match err {
-                        ServerError::TaskNotFound(_) => {
-                            fail_task(server.clone(), task_id,
-                                Error::TaskNotFound(*child_id)).await;
-                            return;
-                        }
-                        _ => {
-                            fail_task(server.clone(), task_id,
-                                Error::TaskFailed(*child_id)).await;
-                            return;
-                        }
+        TaskSpec::TaskList { serial, fail_fast, continue_on_error, .. } => {
+            for (index, child_id) in serial.iter().enumerate() {
+                if let Err(err) = run_child(server.clone(), *child_id).await {
+                    if continue_on_error {
+                        continue;
+                    }
+
+                    if fail_fast {
+                        cancel_children(server.clone(), &serial[index + 1..]).await;
                     }
+
+                    return Err(err);
                 }
+            }
 
-                // Wait for the child task to finish
-                if let Err(_) = wait_task(server.clone(), *child_id).await {
-                    fail_task(server.clone(), task_id, Error::TaskFailed(*child_id))
-                        .await;
-                    return;
+            Ok(())
+        }
+        TaskSpec::Steps { steps, .. } => {
+            let mut waiting = steps;
+            let mut succeeded = HashSet::new();
+            let mut running = FuturesUnordered::new();
+            let mut failure = None;
+
+            loop {
+                // Start every step whose dependencies have all succeeded,
+                // unless a step already failed
+                if failure.is_none() {
+                    let (ready, blocked) = waiting.into_iter().partition(|step| {
+                        step.needs.iter().all(|need| succeeded.contains(need))
+                    });
+                    waiting = blocked;
+
+                    for step in ready {
+                        let handle = tokio::spawn(run_child(server.clone(), step.task));
+                        running.push(async move { (step.name, handle.await) });
+                    }
+                }
+
+                match running.next().await {
+                    Some((name, Ok(Ok(())))) => {
+                        succeeded.insert(name);
+                    }
+                    Some((_, Ok(Err(err)))) => {
+                        failure.get_or_insert(err);
+                    }
+                    Some((_, Err(_))) => {
+                        failure.get_or_insert(Error::TaskFailed(task_id));
+                    }
+                    None => {
+                        break;
+                    }
                 }
             }
 
-            finish_task(server, task_id).await;
+            // Steps left waiting can no longer run
+            let blocked = !waiting.is_empty();
+            for step in waiting {
+                terminate_task(server.clone(), step.task, TaskStatus::Cancelled).await;
+            }
+
+            match failure {
+                Some(err) => Err(err),
+                None if blocked => Err(Error::TaskFailed(task_id)),
+                None => Ok(()),
+            }
         }
     }
 }
 
 
+/// Cancel every child that has not finished yet.
+async fn cancel_children(server: Arc<Server>, children: &[Uuid]) {
+    futures::future::join_all(children.iter().map(|child_id| {
+        terminate_task(server.clone(), *child_id, TaskStatus::Cancelled)
+    })).await;
+}
+
+
+/// Mark a task that is about to wait for a slot as queued.
+async fn queue_task(server: Arc<Server>, task_id: Uuid) {
+    let task = match server.tasks.lock().await.get(&task_id) {
+        Some(task) => task.clone(),
+        None => {
+            return;
+        }
+    };
+
+    let mut task = task.lock().await;
+    if matches!(task.status, TaskStatus::Pending | TaskStatus::Running) {
+        task.status = TaskStatus::Queued;
+        server.persist_task(task_id, &task);
+        server.publish_status(task_id, &task);
+    }
+}
+
+
+/// Start a child task and wait for it to finish.
+async fn run_child(server: Arc<Server>, child_id: Uuid) -> Result<(), Error> {
+    if let Err(err) = start_task(server.clone(), child_id).await {
+        return Err(match err {
+            ServerError::TaskNotFound(_) => Error::TaskNotFound(child_id),
+            _ => Error::TaskFailed(child_id),
+        });
+    }
+
+    if wait_task(server, child_id).await.is_err() {
+        return Err(Error::TaskFailed(child_id));
+    }
+
+    Ok(())
+}
+
+
+/// Return a finished subtree to pending so that it can run again, keeping
+/// the results of the previous run as an attempt.
+fn reset_task(
+    server: Arc<Server>,
+    task_id: Uuid
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        let task = match server.tasks.lock().await.get(&task_id) {
+            Some(task) => task.clone(),
+            None => {
+                return;
+            }
+        };
+
+        let spec = {
+            let mut task = task.lock().await;
+            if task.status == TaskStatus::Pending {
+                return;
+            }
+
+            let status = std::mem::replace(&mut task.status, TaskStatus::Pending);
+            task.reset(status);
+            task.started_at = None;
+            server.persist_task(task_id, &task);
+            server.publish_status(task_id, &task);
+            task.spec.clone()
+        };
+
+        for child_id in spec.children() {
+            reset_task(server.clone(), child_id).await;
+        }
+    })
+}
+
+
+/// Find the exit code of the command at the root of a failure.
+fn failure_exit_code(
+    server: Arc<Server>,
+    error: Error
+) -> Pin<Box<dyn Future<Output = Option<i32>> + Send>> {
+    Box::pin(async move {
+        match error {
+            Error::ExitFailure(status) => status.code(),
+            Error::TaskFailed(child_id) => {
+                let child = server.tasks.lock().await.get(&child_id)?.clone();
+                let error = child.lock().await.error.clone()?;
+                failure_exit_code(server, error).await
+            }
+            _ => None,
+        }
+    })
+}
+
+
 async fn finish_task(
     server: Arc<Server>,
     task_id: Uuid
 ) {
-    if server.verbose {
-        eprintln!("Finished task: {:?}", task_id);
-    }
-
-    if let Some(task) = server.tasks.lock().await.get(&task_id) {
+    // Release the map before touching the task, so that other requests
+    // do not wait on it
+    let task = server.tasks.lock().await.get(&task_id).cloned();
+    if let Some(task) = task {
         let mut task = task.lock().await;
+        if task.status.is_finished() {
+            return;
+        }
+
+        if server.verbose {
+            eprintln!("Finished task: {:?}", task_id);
+        }
+
         task.status = TaskStatus::Success;
+        task.finished_at = Some(SystemTime::now());
         task.finished.notify_waiters();
+        server.persist_task(task_id, &task);
+        server.publish_status(task_id, &task);
     }
 }
 
@@ -177,15 +589,42 @@ async fn fail_task(
     task_id: Uuid,
     error: Error
 ) {
-    if server.verbose {
-        eprintln!("Failed task {}: {:?}", task_id, error);
-    }
-
-    if let Some(task) = server.tasks.lock().await.get(&task_id) {
+    let task = server.tasks.lock().await.get(&task_id).cloned();
+    if let Some(task) = task {
         let mut task = task.lock().await;
+        if task.status.is_finished() {
+            return;
+        }
+
+        if server.verbose {
+            eprintln!("Failed task {}: {:?}", task_id, error);
+        }
+
         task.error = Some(error);
         task.status = TaskStatus::Failure;
+        task.finished_at = Some(SystemTime::now());
         task.finished.notify_waiters();
+        server.persist_task(task_id, &task);
+        server.publish_status(task_id, &task);
+    }
+}
+
+
+async fn record_exit_status(
+    server: Arc<Server>,
+    task_id: Uuid,
+    status: ExitStatus,
+    rusage: Option<Rusage>
+) {
+    let task = server.tasks.lock().await.get(&task_id).cloned();
+    if let Some(task) = task {
+        let mut task = task.lock().await;
+        task.exit_code = status.code();
+        task.signal = status.signal();
+        if let Some(rusage) = rusage {
+            task.rusage.get_or_insert_with(Rusage::default).add(&rusage);
+        }
+        server.persist_task(task_id, &task);
     }
 }
 
@@ -194,32 +633,35 @@ async fn wait_task(
     server: Arc<Server>,
     task_id: Uuid
 ) -> Result<(), Error> {
-    let finished = match server.tasks.lock().await.get(&task_id) {
-        Some(task) => task.lock().await.finished.clone(),
+    let task = match server.tasks.lock().await.get(&task_id) {
+        Some(task) => task.clone(),
         None => {
             return Err(Error::TaskNotFound(task_id));
         }
     };
 
-    finished.notified().await;
-    match server.tasks.lock().await.get(&task_id) {
-        Some(task) => {
+    loop {
+        // Register for the notification before checking the status so a
+        // task finishing in between is not missed
+        let finished = task.lock().await.finished.clone();
+        let notified = finished.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        {
             let task = task.lock().await;
             match task.status {
                 TaskStatus::Success => {
-                    Ok(())
-                }
-                TaskStatus::Failure => {
-                    Err(task.error.clone().unwrap())
+                    return Ok(());
                 }
-                _ => {
-                    eprintln!("Task not finished: {:?}", task_id);
-                    Err(Error::TaskFailed(task_id))
+                ref status if status.is_finished() => {
+                    return Err(task.error.clone()
+                        .unwrap_or(Error::TaskFailed(task_id)));
                 }
+                _ => {}
             }
         }
-        None => {
-            Err(Error::TaskNotFound(task_id))
-        }
+
+        notified.await;
     }
 }
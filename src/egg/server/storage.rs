@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::plans::CreatePlan;
+use crate::tasks::Task;
+
+
+/// Durable record of the server's plans and tasks.
+///
+/// The server keeps its working set in memory and writes every change
+/// through to the storage, which is read back once at startup.
+pub trait Storage: Send + Sync {
+    fn load(&self) -> Result<(Vec<PlanRecord>, Vec<TaskRecord>), Error>;
+    fn save_plan(&self, plan: &PlanRecord) -> Result<(), Error>;
+    fn save_task(&self, task: &TaskRecord) -> Result<(), Error>;
+    fn delete_plan(&self, id: Uuid) -> Result<(), Error>;
+    fn delete_task(&self, id: Uuid) -> Result<(), Error>;
+}
+
+
+/// A change to the storage, queued for its writer.
+#[derive(Debug)]
+pub enum StorageWrite {
+    SavePlan(PlanRecord),
+    SaveTask(Box<TaskRecord>),
+    DeletePlan(Uuid),
+    DeleteTask(Uuid),
+}
+
+impl StorageWrite {
+    /// What the change does and to which plan or task, for logging.
+    fn describe(&self) -> (&'static str, Uuid) {
+        match self {
+            StorageWrite::SavePlan(plan) => ("save plan", plan.id),
+            StorageWrite::SaveTask(task) => ("save task", task.task.id),
+            StorageWrite::DeletePlan(id) => ("delete plan", *id),
+            StorageWrite::DeleteTask(id) => ("delete task", *id),
+        }
+    }
+}
+
+
+/// Applies changes to a storage in order on a thread of its own, so that
+/// the server never waits on the disk.
+#[derive(Debug)]
+pub struct StorageWriter {
+    /// Taken once the writer is closed
+    sender: Mutex<Option<Sender<StorageWrite>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl StorageWriter {
+    pub fn spawn(storage: Arc<dyn Storage>) -> Self {
+        let (sender, receiver) = channel::<StorageWrite>();
+        let thread = std::thread::spawn(move || {
+            for write in receiver {
+                let (action, id) = write.describe();
+                let result = match write {
+                    StorageWrite::SavePlan(plan) => storage.save_plan(&plan),
+                    StorageWrite::SaveTask(task) => storage.save_task(&task),
+                    StorageWrite::DeletePlan(id) => storage.delete_plan(id),
+                    StorageWrite::DeleteTask(id) => storage.delete_task(id),
+                };
+
+                if let Err(err) = result {
+                    eprintln!("Failed to {} {}: {:?}", action, id, err);
+                }
+            }
+        });
+
+        Self {
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    pub fn write(&self, write: StorageWrite) {
+        let sender = self.sender.lock().expect("storage writer lock poisoned");
+        let queued = match sender.as_ref() {
+            Some(sender) => sender.send(write).map_err(|err| err.0),
+            None => Err(write),
+        };
+
+        // Sending fails once the writer is closed or its thread has died
+        if let Err(write) = queued {
+            let (action, id) = write.describe();
+            eprintln!("Failed to {} {}: storage writer is not running", action, id);
+        }
+    }
+
+    /// Stop taking writes and wait until the queued ones are applied.
+    pub fn close(&self) {
+        self.sender.lock().expect("storage writer lock poisoned").take();
+
+        let thread = self.thread.lock().expect("storage writer lock poisoned").take();
+        if let Some(Err(_)) = thread.map(JoinHandle::join) {
+            eprintln!("Storage writer stopped before applying every write");
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlanRecord {
+    pub id: Uuid,
+    pub versions: Vec<CreatePlan>,
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskRecord {
+    pub task: Task,
+    pub deadline: Option<Duration>,
+}
+
+
+/// Storage that keeps nothing beyond the server's own maps, so plans and
+/// tasks last for the life of the process.
+#[derive(Debug, Default)]
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> Result<(Vec<PlanRecord>, Vec<TaskRecord>), Error> {
+        Ok((vec![], vec![]))
+    }
+
+    fn save_plan(&self, _plan: &PlanRecord) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn save_task(&self, _task: &TaskRecord) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn delete_plan(&self, _id: Uuid) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn delete_task(&self, _id: Uuid) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+
+/// Storage that keeps one JSON file per plan and per task under a data
+/// directory.
+#[derive(Debug)]
+pub struct DiskStorage {
+    dir: PathBuf,
+}
+
+impl DiskStorage {
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir.join("plans")).map_err(storage_error)?;
+        std::fs::create_dir_all(dir.join("tasks")).map_err(storage_error)?;
+        Ok(Self { dir })
+    }
+
+    fn load_dir<T: for<'de> Deserialize<'de>>(&self, name: &str) -> Result<Vec<T>, Error> {
+        let mut records = vec![];
+        for entry in std::fs::read_dir(self.dir.join(name)).map_err(storage_error)? {
+            let path = entry.map_err(storage_error)?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let data = std::fs::read(&path).map_err(storage_error)?;
+                records.push(serde_json::from_slice(&data)
+                    .map_err(|err| storage_error(err.into()))?);
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn save<T: Serialize>(&self, name: &str, id: Uuid, record: &T) -> Result<(), Error> {
+        let data = serde_json::to_vec(record).map_err(|err| storage_error(err.into()))?;
+        let path = self.dir.join(name).join(format!("{}.json", id));
+        let tmp = path.with_extension("json.tmp");
+
+        // Write a temporary file first so that a crash never leaves a
+        // truncated record behind
+        std::fs::write(&tmp, data).map_err(storage_error)?;
+        std::fs::rename(&tmp, &path).map_err(storage_error)
+    }
+
+    fn delete(&self, name: &str, id: Uuid) -> Result<(), Error> {
+        let path = self.dir.join(name).join(format!("{}.json", id));
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(storage_error(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Storage for DiskStorage {
+    fn load(&self) -> Result<(Vec<PlanRecord>, Vec<TaskRecord>), Error> {
+        Ok((self.load_dir("plans")?, self.load_dir("tasks")?))
+    }
+
+    fn save_plan(&self, plan: &PlanRecord) -> Result<(), Error> {
+        self.save("plans", plan.id, plan)
+    }
+
+    fn save_task(&self, task: &TaskRecord) -> Result<(), Error> {
+        self.save("tasks", task.task.id, task)
+    }
+
+    fn delete_plan(&self, id: Uuid) -> Result<(), Error> {
+        self.delete("plans", id)
+    }
+
+    fn delete_task(&self, id: Uuid) -> Result<(), Error> {
+        self.delete("tasks", id)
+    }
+}
+
+
+fn storage_error(err: std::io::Error) -> Error {
+    Error::StorageFailed(Arc::new(err))
+}
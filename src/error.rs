@@ -8,8 +8,15 @@ pub enum Error {
     CommandFailed(Arc<std::io::Error>),
     ExitFailure(std::process::ExitStatus),
     PlanNotFound(Uuid),
+    InvalidPlan(String),
+    InvalidParams(String),
     TaskNotFound(Uuid),
     TaskFailed(Uuid),
+    TimedOut(Uuid),
+    Cancelled(Uuid),
+    Terminated,
+    StdinClosed,
+    StorageFailed(Arc<std::io::Error>),
 }
 
 impl std::fmt::Debug for Error {
@@ -27,12 +34,33 @@ impl std::fmt::Debug for Error {
             Error::PlanNotFound(id) => {
                 write!(f, "Plan not found: {:?}", id)
             }
+            Error::InvalidPlan(reason) => {
+                write!(f, "Invalid plan: {}", reason)
+            }
+            Error::InvalidParams(reason) => {
+                write!(f, "Invalid parameters: {}", reason)
+            }
             Error::TaskNotFound(id) => {
                 write!(f, "Task not found: {:?}", id)
             }
             Error::TaskFailed(id) => {
                 write!(f, "Task failed: {:?}", id)
             }
+            Error::TimedOut(id) => {
+                write!(f, "Task timed out: {:?}", id)
+            }
+            Error::Cancelled(id) => {
+                write!(f, "Task cancelled: {:?}", id)
+            }
+            Error::Terminated => {
+                write!(f, "Command terminated before it started")
+            }
+            Error::StdinClosed => {
+                write!(f, "Standard input is not open")
+            }
+            Error::StorageFailed(err) => {
+                write!(f, "Storage failed: {:?}", err)
+            }
         }
     }
 }
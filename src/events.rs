@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::plans::Plan;
+use crate::tasks::{TaskPlan, TaskStatus};
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Event {
+    PlanCreated(Plan),
+    PlanUpdated(Plan),
+    TaskStatus {
+        id: Uuid,
+        plan: Option<TaskPlan>,
+        status: TaskStatus,
+    },
+    /// Sent to a subscriber that fell behind and missed this many events,
+    /// which should fetch the current state again
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl Event {
+    pub fn plan_id(&self) -> Option<Uuid> {
+        match self {
+            Event::PlanCreated(plan) | Event::PlanUpdated(plan) => Some(plan.id),
+            Event::TaskStatus { plan, .. } => plan.as_ref().map(|plan| plan.id),
+            Event::Lagged { .. } => None,
+        }
+    }
+
+    pub fn task_id(&self) -> Option<Uuid> {
+        match self {
+            Event::PlanCreated(_) | Event::PlanUpdated(_) => None,
+            Event::TaskStatus { id, .. } => Some(*id),
+            Event::Lagged { .. } => None,
+        }
+    }
+}
+
+
+/// Selects events by plan and task. Unset fields match every event.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EventFilter {
+    pub plan: Option<Uuid>,
+    pub task: Option<Uuid>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.plan.is_none_or(|plan| event.plan_id() == Some(plan)) &&
+            self.task.is_none_or(|task| event.task_id() == Some(task))
+    }
+}
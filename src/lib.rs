@@ -1,6 +1,7 @@
 // File: src/lib.rs
 pub mod egg;
 pub mod error;
+pub mod events;
 pub mod plans;
 pub mod process;
 pub mod tasks;
@@ -1,25 +1,776 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
 use uuid::Uuid;
 
+use crate::error::Error;
+use crate::process::Stdin;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+pub mod diff;
+
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct CreatePlan {
+    /// Unique name the plan can be addressed by instead of its id
+    #[serde(default)]
+    pub name: Option<String>,
     pub spec: PlanSpec,
+    #[serde(default)]
+    pub deadline: Option<u64>,
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamSpec>,
+}
+
+impl CreatePlan {
+    pub fn to_plan(&self, id: Uuid, version: u64, created_at: SystemTime) -> Plan {
+        Plan {
+            id,
+            name: self.name.clone(),
+            spec: self.spec.clone(),
+            deadline: self.deadline,
+            params: self.params.clone(),
+            version,
+            created_at: Some(created_at),
+        }
+    }
+
+    /// Check the spec, the parameter defaults and that every parameter
+    /// referenced by a command is declared.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.spec.validate()?;
+
+        for (name, param) in &self.params {
+            if let Some(ref default) = param.default {
+                if param.kind.coerce(default.clone()).is_none() {
+                    return Err(Error::InvalidPlan(
+                        format!("Default of parameter {} is not {:?}", name, param.kind)
+                    ));
+                }
+            }
+        }
+
+        let declared = self.params.keys()
+            .map(|name| (name.clone(), Value::Null))
+            .collect();
+        self.spec.clone().substitute("params", &declared)?;
+
+        // Matrix values are only defined inside their matrix
+        self.spec.clone().substitute("matrix", &BTreeMap::new())?;
+        Ok(())
+    }
+
+    /// Check parameter values against the declared parameters, filling in
+    /// defaults for those without a value.
+    pub fn resolve_params(
+        &self,
+        mut values: BTreeMap<String, Value>
+    ) -> Result<BTreeMap<String, Value>, Error> {
+        let mut resolved = BTreeMap::new();
+
+        for (name, param) in &self.params {
+            let value = match values.remove(name).or_else(|| param.default.clone()) {
+                Some(value) => value,
+                None => {
+                    return Err(Error::InvalidParams(
+                        format!("Missing parameter: {}", name)
+                    ));
+                }
+            };
+
+            match param.kind.coerce(value) {
+                Some(value) => {
+                    resolved.insert(name.clone(), value);
+                }
+                None => {
+                    return Err(Error::InvalidParams(
+                        format!("Parameter {} is not {:?}", name, param.kind)
+                    ));
+                }
+            }
+        }
+
+        if let Some(name) = values.keys().next() {
+            return Err(Error::InvalidParams(format!("Unknown parameter: {}", name)));
+        }
+
+        Ok(resolved)
+    }
 }
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Plan {
     pub id: Uuid,
+    #[serde(default)]
+    pub name: Option<String>,
     pub spec: PlanSpec,
+    pub deadline: Option<u64>,
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamSpec>,
     pub version: u64,
+    /// When the first version of the plan was created
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
 }
 
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ParamSpec {
+    #[serde(rename = "type", default)]
+    pub kind: ParamType,
+    /// Used when no value is given; parameters without one are required
+    #[serde(default)]
+    pub default: Option<Value>,
+}
+
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl ParamType {
+    /// Check that a value has this type. Strings are parsed into the other
+    /// types so that values can be given on the command line.
+    pub fn coerce(&self, value: Value) -> Option<Value> {
+        match (self, value) {
+            (ParamType::String, value @ Value::String(_)) => Some(value),
+            (ParamType::Integer, Value::Number(number)) if number.is_i64() || number.is_u64() => {
+                Some(Value::Number(number))
+            }
+            (ParamType::Integer, Value::String(text)) => {
+                text.trim().parse::<i64>().ok().map(Value::from)
+            }
+            (ParamType::Number, value @ Value::Number(_)) => Some(value),
+            (ParamType::Number, Value::String(text)) => {
+                text.trim().parse::<f64>().ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number)
+            }
+            (ParamType::Boolean, value @ Value::Bool(_)) => Some(value),
+            (ParamType::Boolean, Value::String(text)) => {
+                text.trim().parse::<bool>().ok().map(Value::Bool)
+            }
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum PlanSpec {
-    Command { args: Vec<String> },
-    TaskGroup { parallel: Vec<PlanSpec> },
-    TaskList { serial: Vec<PlanSpec> },
+    Command {
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        clear_env: bool,
+        #[serde(default)]
+        stdin: Option<Stdin>,
+        #[serde(default)]
+        timeout: Option<u64>,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    Script {
+        script: String,
+        /// Shell that runs the script with `-e -u`, defaulting to `sh`
+        #[serde(default)]
+        shell: Option<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        clear_env: bool,
+        #[serde(default)]
+        stdin: Option<Stdin>,
+        #[serde(default)]
+        timeout: Option<u64>,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    TaskGroup {
+        parallel: Vec<PlanSpec>,
+        /// Run at most this many children at once
+        #[serde(default)]
+        max_parallel: Option<usize>,
+        /// Cancel the remaining children as soon as one fails
+        #[serde(default)]
+        fail_fast: bool,
+        /// Run every child and succeed even if some of them fail
+        #[serde(default)]
+        continue_on_error: bool,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    TaskList {
+        serial: Vec<PlanSpec>,
+        /// Cancel the remaining children as soon as one fails
+        #[serde(default)]
+        fail_fast: bool,
+        /// Run every child and succeed even if some of them fail
+        #[serde(default)]
+        continue_on_error: bool,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    Steps {
+        steps: Vec<PlanStep>,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    /// Runs `run` in parallel once for every combination of values
+    Matrix {
+        matrix: BTreeMap<String, Vec<Value>>,
+        run: Box<PlanSpec>,
+        #[serde(default)]
+        max_parallel: Option<usize>,
+        #[serde(default)]
+        fail_fast: bool,
+        #[serde(default)]
+        continue_on_error: bool,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+}
+
+impl PlanSpec {
+    /// Check that commands have something to run, and that steps have unique
+    /// names, only need steps that exist and do not depend on each other in
+    /// a cycle.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            PlanSpec::Command { args, .. } => {
+                if args.is_empty() {
+                    return Err(Error::InvalidPlan("Command has no args".to_string()));
+                }
+
+                Ok(())
+            }
+            PlanSpec::Script { shell, .. } => {
+                if shell.as_ref().is_some_and(|shell| shell.is_empty()) {
+                    return Err(Error::InvalidPlan("Script has an empty shell".to_string()));
+                }
+
+                Ok(())
+            }
+            PlanSpec::TaskGroup { parallel: children, fail_fast, continue_on_error, .. } |
+            PlanSpec::TaskList { serial: children, fail_fast, continue_on_error, .. } => {
+                if *fail_fast && *continue_on_error {
+                    return Err(Error::InvalidPlan(
+                        "fail_fast and continue_on_error are mutually exclusive".to_string()
+                    ));
+                }
+
+                children.iter().try_for_each(PlanSpec::validate)
+            }
+            PlanSpec::Steps { steps, .. } => {
+                let mut names = BTreeSet::new();
+                for step in steps {
+                    if !names.insert(step.name.as_str()) {
+                        return Err(Error::InvalidPlan(
+                            format!("Duplicate step: {}", step.name)
+                        ));
+                    }
+                }
+
+                for step in steps {
+                    if let Some(need) = step.needs.iter().find(|need| !names.contains(need.as_str())) {
+                        return Err(Error::InvalidPlan(
+                            format!("Step {} needs unknown step: {}", step.name, need)
+                        ));
+                    }
+                }
+
+                // Resolve steps whose needs are all resolved until none are
+                // left, or the rest depend on each other
+                let mut resolved = BTreeSet::new();
+                let mut remaining: Vec<&PlanStep> = steps.iter().collect();
+                while !remaining.is_empty() {
+                    let (ready, blocked): (Vec<&PlanStep>, Vec<&PlanStep>) = remaining
+                        .into_iter()
+                        .partition(|step| {
+                            step.needs.iter().all(|need| resolved.contains(need.as_str()))
+                        });
+
+                    if ready.is_empty() {
+                        let names: Vec<&str> = blocked.iter().map(|step| step.name.as_str()).collect();
+                        return Err(Error::InvalidPlan(
+                            format!("Dependency cycle between steps: {}", names.join(", "))
+                        ));
+                    }
+
+                    resolved.extend(ready.iter().map(|step| step.name.as_str()));
+                    remaining = blocked;
+                }
+
+                steps.iter().try_for_each(|step| step.spec.validate())
+            }
+            PlanSpec::Matrix { matrix, run, fail_fast, continue_on_error, .. } => {
+                if *fail_fast && *continue_on_error {
+                    return Err(Error::InvalidPlan(
+                        "fail_fast and continue_on_error are mutually exclusive".to_string()
+                    ));
+                }
+
+                if let Some((name, _)) = matrix.iter().find(|(_, values)| values.is_empty()) {
+                    return Err(Error::InvalidPlan(format!("Matrix {} has no values", name)));
+                }
+
+                run.validate()
+            }
+        }
+    }
+
+    /// Replace `${<namespace>.<name>}` references in command arguments and
+    /// environment values, such as `${params.branch}`. Values are not pasted
+    /// into scripts, which instead read them from environment variables
+    /// such as `EGG_PARAM_BRANCH` that the references are replaced with.
+    pub fn substitute(
+        self,
+        namespace: &str,
+        values: &BTreeMap<String, Value>
+    ) -> Result<PlanSpec, Error> {
+        let substitute_all = |specs: Vec<PlanSpec>| -> Result<Vec<PlanSpec>, Error> {
+            specs.into_iter().map(|spec| spec.substitute(namespace, values)).collect()
+        };
+
+        Ok(match self {
+            PlanSpec::Command { args, env, cwd, clear_env, stdin, timeout, retry } => {
+                PlanSpec::Command {
+                    args: args.iter()
+                        .map(|arg| substitute_text(arg, namespace, values))
+                        .collect::<Result<_, _>>()?,
+                    env: env.iter()
+                        .map(|(key, value)| {
+                            Ok((key.clone(), substitute_text(value, namespace, values)?))
+                        })
+                        .collect::<Result<_, Error>>()?,
+                    cwd,
+                    clear_env,
+                    stdin,
+                    timeout,
+                    retry,
+                }
+            }
+            PlanSpec::Script { script, shell, env, cwd, clear_env, stdin, timeout, retry } => {
+                let mut env = env.iter()
+                    .map(|(key, value)| {
+                        Ok((key.clone(), substitute_text(value, namespace, values)?))
+                    })
+                    .collect::<Result<BTreeMap<_, _>, Error>>()?;
+
+                PlanSpec::Script {
+                    script: substitute_script(&script, namespace, values, &mut env)?,
+                    shell,
+                    env,
+                    cwd,
+                    clear_env,
+                    stdin,
+                    timeout,
+                    retry,
+                }
+            }
+            PlanSpec::TaskGroup { parallel, max_parallel, fail_fast, continue_on_error, retry } => {
+                PlanSpec::TaskGroup {
+                    parallel: substitute_all(parallel)?,
+                    max_parallel,
+                    fail_fast,
+                    continue_on_error,
+                    retry,
+                }
+            }
+            PlanSpec::TaskList { serial, fail_fast, continue_on_error, retry } => {
+                PlanSpec::TaskList {
+                    serial: substitute_all(serial)?,
+                    fail_fast,
+                    continue_on_error,
+                    retry,
+                }
+            }
+            PlanSpec::Steps { steps, retry } => {
+                PlanSpec::Steps {
+                    steps: steps.into_iter()
+                        .map(|step| {
+                            Ok(PlanStep { spec: step.spec.substitute(namespace, values)?, ..step })
+                        })
+                        .collect::<Result<_, Error>>()?,
+                    retry,
+                }
+            }
+            PlanSpec::Matrix { matrix, run, max_parallel, fail_fast, continue_on_error, retry } => {
+                // A nested matrix shadows the values it defines, which are
+                // left in place until it is expanded itself
+                let mut values = values.clone();
+                if namespace == "matrix" {
+                    for name in matrix.keys() {
+                        let reference = format!("${{matrix.{}}}", name);
+                        values.insert(name.clone(), Value::String(reference));
+                    }
+                }
+
+                PlanSpec::Matrix {
+                    run: Box::new(run.substitute(namespace, &values)?),
+                    matrix,
+                    max_parallel,
+                    fail_fast,
+                    continue_on_error,
+                    retry,
+                }
+            }
+        })
+    }
+}
+
+
+/// Every combination of one value per name, in order of the names.
+pub fn matrix_combinations(
+    matrix: &BTreeMap<String, Vec<Value>>
+) -> Vec<BTreeMap<String, Value>> {
+    let mut combinations = vec![BTreeMap::new()];
+    for (name, values) in matrix {
+        combinations = combinations.into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    combinations
+}
+
+
+/// Expand every `${<namespace>.<name>}` in `text`.
+fn substitute_text(
+    text: &str,
+    namespace: &str,
+    values: &BTreeMap<String, Value>
+) -> Result<String, Error> {
+    substitute_with(text, namespace, values, |_, value| value_text(value))
+}
+
+
+/// Replace every `${<namespace>.<name>}` in a script with a reference to an
+/// environment variable holding the value, so that values are never parsed
+/// as shell code. References a nested matrix shadows are left in place.
+fn substitute_script(
+    script: &str,
+    namespace: &str,
+    values: &BTreeMap<String, Value>,
+    env: &mut BTreeMap<String, String>
+) -> Result<String, Error> {
+    substitute_with(script, namespace, values, |name, value| {
+        let reference = format!("${{{}.{}}}", namespace, name);
+        if *value == Value::String(reference.clone()) {
+            return reference;
+        }
+
+        let variable = env_name(namespace, name);
+        env.insert(variable.clone(), value_text(value));
+        format!("${{{}}}", variable)
+    })
+}
+
+
+/// Environment variable a script reads a value from, such as
+/// `EGG_PARAM_BRANCH` for `${params.branch}`.
+fn env_name(namespace: &str, name: &str) -> String {
+    let namespace = match namespace {
+        "params" => "param",
+        namespace => namespace,
+    };
+
+    format!("EGG_{}_{}", namespace, name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+
+fn substitute_with(
+    text: &str,
+    namespace: &str,
+    values: &BTreeMap<String, Value>,
+    mut replace: impl FnMut(&str, &Value) -> String
+) -> Result<String, Error> {
+    let prefix = format!("${{{}.", namespace);
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(&prefix) {
+        let reference = &rest[start + prefix.len()..];
+        let end = reference.find('}').ok_or_else(|| {
+            Error::InvalidPlan(format!("Unterminated {} reference: {}", namespace, text))
+        })?;
+
+        let name = &reference[..end];
+        let value = values.get(name).ok_or_else(|| {
+            Error::InvalidPlan(format!("Unknown {} value: {}", namespace, name))
+        })?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&replace(name, value));
+        rest = &reference[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PlanStep {
+    pub name: String,
+    /// Names of the steps that must succeed before this one starts
+    #[serde(default)]
+    pub needs: Vec<String>,
+    #[serde(flatten)]
+    pub spec: PlanSpec,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Seconds to wait before the first retry, doubled for every retry after
+    #[serde(default)]
+    pub backoff: u64,
+    /// Only retry failures with one of these exit codes; empty retries all.
+    /// Timed out attempts are always retried.
+    #[serde(default)]
+    pub on_exit_codes: Vec<i32>,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn spec(value: Value) -> PlanSpec {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn scripts_read_values_from_the_environment() {
+        let values = BTreeMap::from([("branch".to_string(), json!("main; rm -rf /"))]);
+        let script = spec(json!({"script": "git checkout ${params.branch}"}))
+            .substitute("params", &values)
+            .unwrap();
+
+        match script {
+            PlanSpec::Script { script, env, .. } => {
+                assert_eq!(script, "git checkout ${EGG_PARAM_BRANCH}");
+                assert_eq!(env["EGG_PARAM_BRANCH"], "main; rm -rf /");
+            }
+            spec => panic!("unexpected spec {:?}", spec),
+        }
+    }
+
+    fn invalid_plan(spec: PlanSpec) -> String {
+        match spec.validate() {
+            Err(Error::InvalidPlan(reason)) => reason,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn commands_need_args() {
+        assert_eq!(invalid_plan(spec(json!({"args": []}))), "Command has no args");
+        assert_eq!(
+            invalid_plan(spec(json!({"serial": [{"args": ["true"]}, {"args": []}]}))),
+            "Command has no args"
+        );
+    }
+
+    #[test]
+    fn steps_are_checked() {
+        let steps = |steps: Value| spec(json!({ "steps": steps }));
+
+        assert!(steps(json!([
+            {"name": "build", "args": ["make"]},
+            {"name": "test", "needs": ["build"], "args": ["make", "test"]},
+        ])).validate().is_ok());
+        assert_eq!(
+            invalid_plan(steps(json!([
+                {"name": "build", "args": ["make"]},
+                {"name": "build", "args": ["make", "test"]},
+            ]))),
+            "Duplicate step: build"
+        );
+        assert_eq!(
+            invalid_plan(steps(json!([
+                {"name": "test", "needs": ["build"], "args": ["make", "test"]},
+            ]))),
+            "Step test needs unknown step: build"
+        );
+        assert_eq!(
+            invalid_plan(steps(json!([
+                {"name": "build", "args": ["make"]},
+                {"name": "a", "needs": ["build", "b"], "args": ["true"]},
+                {"name": "b", "needs": ["a"], "args": ["true"]},
+            ]))),
+            "Dependency cycle between steps: a, b"
+        );
+    }
+
+    #[test]
+    fn param_values_are_coerced() {
+        assert_eq!(ParamType::String.coerce(json!("main")), Some(json!("main")));
+        assert_eq!(ParamType::String.coerce(json!(1)), None);
+        assert_eq!(ParamType::Integer.coerce(json!(3)), Some(json!(3)));
+        assert_eq!(ParamType::Integer.coerce(json!(" 3 ")), Some(json!(3)));
+        assert_eq!(ParamType::Integer.coerce(json!(1.5)), None);
+        assert_eq!(ParamType::Integer.coerce(json!("three")), None);
+        assert_eq!(ParamType::Number.coerce(json!("1.5")), Some(json!(1.5)));
+        assert_eq!(ParamType::Number.coerce(json!("NaN")), None);
+        assert_eq!(ParamType::Boolean.coerce(json!("true")), Some(json!(true)));
+        assert_eq!(ParamType::Boolean.coerce(json!("yes")), None);
+    }
+
+    #[test]
+    fn references_are_substituted() {
+        let values = BTreeMap::from([
+            ("branch".to_string(), json!("main")),
+            ("jobs".to_string(), json!(4)),
+        ]);
+
+        assert_eq!(
+            substitute_text("-b ${params.branch} -j${params.jobs}", "params", &values).unwrap(),
+            "-b main -j4"
+        );
+        assert_eq!(substitute_text("${matrix.os}", "params", &values).unwrap(), "${matrix.os}");
+        assert!(matches!(
+            substitute_text("${params.tag}", "params", &values),
+            Err(Error::InvalidPlan(reason)) if reason == "Unknown params value: tag"
+        ));
+        assert!(matches!(
+            substitute_text("-b ${params.branch", "params", &values),
+            Err(Error::InvalidPlan(reason)) if reason == "Unterminated params reference: -b ${params.branch"
+        ));
+    }
+
+    #[test]
+    fn plans_only_reference_declared_params() {
+        let plan = |args: Value| -> CreatePlan {
+            serde_json::from_value(json!({
+                "spec": {"args": args},
+                "params": {"branch": {}},
+            })).unwrap()
+        };
+
+        assert!(plan(json!(["git", "checkout", "${params.branch}"])).validate().is_ok());
+        assert!(plan(json!(["git", "checkout", "${params.tag}"])).validate().is_err());
+        assert!(plan(json!(["git", "checkout", "${params.branch"])).validate().is_err());
+        assert!(plan(json!(["echo", "${matrix.os}"])).validate().is_err());
+    }
+
+    #[test]
+    fn params_are_resolved() {
+        let plan: CreatePlan = serde_json::from_value(json!({
+            "spec": {"args": ["make", "-j${params.jobs}"]},
+            "params": {
+                "jobs": {"type": "integer", "default": 1},
+                "release": {"type": "boolean"},
+            },
+        })).unwrap();
+        let params = |values: Value| {
+            plan.resolve_params(serde_json::from_value(values).unwrap())
+        };
+
+        assert_eq!(
+            params(json!({"release": "true"})).unwrap(),
+            BTreeMap::from([
+                ("jobs".to_string(), json!(1)),
+                ("release".to_string(), json!(true)),
+            ])
+        );
+        assert!(matches!(
+            params(json!({"jobs": "8"})),
+            Err(Error::InvalidParams(reason)) if reason == "Missing parameter: release"
+        ));
+        assert!(matches!(
+            params(json!({"release": true, "jobs": "many"})),
+            Err(Error::InvalidParams(reason)) if reason == "Parameter jobs is not Integer"
+        ));
+        assert!(matches!(
+            params(json!({"release": true, "debug": true})),
+            Err(Error::InvalidParams(reason)) if reason == "Unknown parameter: debug"
+        ));
+    }
+
+    #[test]
+    fn matrix_combinations_cover_every_value() {
+        let matrix = BTreeMap::from([
+            ("os".to_string(), vec![json!("linux"), json!("macos")]),
+            ("jobs".to_string(), vec![json!(1), json!(2), json!(4)]),
+        ]);
+        let combinations = matrix_combinations(&matrix);
+
+        assert_eq!(combinations.len(), 6);
+        assert_eq!(combinations[0], BTreeMap::from([
+            ("jobs".to_string(), json!(1)),
+            ("os".to_string(), json!("linux")),
+        ]));
+        assert_eq!(combinations[1], BTreeMap::from([
+            ("jobs".to_string(), json!(1)),
+            ("os".to_string(), json!("macos")),
+        ]));
+        assert_eq!(matrix_combinations(&BTreeMap::new()), vec![BTreeMap::new()]);
+    }
+
+    #[test]
+    fn nested_matrices_shadow_their_values() {
+        let outer = spec(json!({
+            "matrix": {"os": ["linux"]},
+            "run": {
+                "matrix": {"arch": ["arm64"], "os": ["macos"]},
+                "run": {"args": ["build", "${matrix.os}", "${matrix.arch}"]},
+            },
+        }));
+        assert!(outer.validate().is_ok());
+
+        let PlanSpec::Matrix { run, .. } = outer else {
+            unreachable!();
+        };
+        let values = BTreeMap::from([("os".to_string(), json!("linux"))]);
+        let inner = run.substitute("matrix", &values).unwrap();
+        assert_eq!(inner, spec(json!({
+            "matrix": {"arch": ["arm64"], "os": ["macos"]},
+            "run": {"args": ["build", "${matrix.os}", "${matrix.arch}"]},
+        })));
+
+        // Values the inner matrix does not define come from the outer one
+        let inner = spec(json!({
+            "matrix": {"arch": ["arm64"]},
+            "run": {"args": ["build", "${matrix.os}", "${matrix.arch}"]},
+        }));
+        assert_eq!(inner.substitute("matrix", &values).unwrap(), spec(json!({
+            "matrix": {"arch": ["arm64"]},
+            "run": {"args": ["build", "linux", "${matrix.arch}"]},
+        })));
+    }
 }
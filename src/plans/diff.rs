@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::plans::{CreatePlan, PlanSpec, PlanStep};
+
+
+/// Keys of a node that hold its children rather than its own settings.
+const CHILD_KEYS: [&str; 4] = ["parallel", "serial", "steps", "run"];
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlanDiff {
+    pub from: u64,
+    pub to: u64,
+    pub changes: Vec<Change>,
+}
+
+
+/// A difference between two versions of a plan. Paths locate nodes from the
+/// root `spec`, such as `spec.serial[1].parallel[0]` or `spec.steps[lint]`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    Added {
+        path: String,
+        node: PlanSpec,
+    },
+    Removed {
+        path: String,
+        node: PlanSpec,
+    },
+    Changed {
+        path: String,
+        field: String,
+        from: Value,
+        to: Value,
+    },
+}
+
+
+/// Compare two versions of a plan node by node.
+pub fn diff(from: &CreatePlan, to: &CreatePlan) -> Vec<Change> {
+    let mut changes = vec![];
+
+    let fields = [
+        ("name", to_value(&from.name), to_value(&to.name)),
+        ("deadline", to_value(&from.deadline), to_value(&to.deadline)),
+        ("params", to_value(&from.params), to_value(&to.params)),
+    ];
+    for (field, before, after) in fields {
+        if before != after {
+            changes.push(Change::Changed {
+                path: String::new(),
+                field: field.to_string(),
+                from: before,
+                to: after,
+            });
+        }
+    }
+
+    diff_node("spec".to_string(), &from.spec, &to.spec, &mut changes);
+    changes
+}
+
+
+fn diff_node(path: String, from: &PlanSpec, to: &PlanSpec, changes: &mut Vec<Change>) {
+    if kind(from) != kind(to) {
+        changes.push(Change::Removed { path: path.clone(), node: from.clone() });
+        changes.push(Change::Added { path, node: to.clone() });
+        return;
+    }
+
+    diff_fields(&path, to_value(from), to_value(to), changes);
+
+    match (from, to) {
+        (PlanSpec::TaskGroup { parallel: from, .. }, PlanSpec::TaskGroup { parallel: to, .. }) => {
+            diff_children(&format!("{}.parallel", path), from, to, changes);
+        }
+        (PlanSpec::TaskList { serial: from, .. }, PlanSpec::TaskList { serial: to, .. }) => {
+            diff_children(&format!("{}.serial", path), from, to, changes);
+        }
+        (PlanSpec::Steps { steps: from, .. }, PlanSpec::Steps { steps: to, .. }) => {
+            diff_steps(&path, from, to, changes);
+        }
+        (PlanSpec::Matrix { run: from, .. }, PlanSpec::Matrix { run: to, .. }) => {
+            diff_node(format!("{}.run", path), from, to, changes);
+        }
+        _ => {}
+    }
+}
+
+
+/// Record every setting of a node that differs, leaving out its children.
+fn diff_fields(path: &str, from: Value, to: Value, changes: &mut Vec<Change>) {
+    let (Value::Object(mut from), Value::Object(mut to)) = (from, to) else {
+        return;
+    };
+
+    let mut fields: Vec<String> = from.keys().chain(to.keys()).cloned().collect();
+    fields.sort();
+    fields.dedup();
+
+    for field in fields {
+        if CHILD_KEYS.contains(&field.as_str()) {
+            continue;
+        }
+
+        let before = from.remove(&field).unwrap_or(Value::Null);
+        let after = to.remove(&field).unwrap_or(Value::Null);
+        if before != after {
+            changes.push(Change::Changed { path: path.to_string(), field, from: before, to: after });
+        }
+    }
+}
+
+
+/// Align two lists of children on their longest common subsequence, so
+/// that inserting or removing a child does not show every later child as
+/// changed. Unmatched children in the same gap are compared pairwise.
+fn diff_children(path: &str, from: &[PlanSpec], to: &[PlanSpec], changes: &mut Vec<Change>) {
+    let mut lengths = vec![vec![0usize; to.len() + 1]; from.len() + 1];
+    for i in (0..from.len()).rev() {
+        for j in (0..to.len()).rev() {
+            lengths[i][j] = if from[i] == to[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut matches = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < from.len() && j < to.len() {
+        if from[i] == to[j] {
+            matches.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matches.push((from.len(), to.len()));
+
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in matches {
+        let removed = &from[i..next_i];
+        let added = &to[j..next_j];
+
+        for offset in 0..removed.len().max(added.len()) {
+            match (removed.get(offset), added.get(offset)) {
+                (Some(before), Some(after)) => {
+                    let path = format!("{}[{}]", path, j + offset);
+                    diff_node(path, before, after, changes);
+                }
+                (Some(before), None) => {
+                    let path = format!("{}[{}]", path, i + offset);
+                    changes.push(Change::Removed { path, node: before.clone() });
+                }
+                (None, Some(after)) => {
+                    let path = format!("{}[{}]", path, j + offset);
+                    changes.push(Change::Added { path, node: after.clone() });
+                }
+                (None, None) => {}
+            }
+        }
+
+        i = next_i + 1;
+        j = next_j + 1;
+    }
+}
+
+
+/// Match steps by name.
+fn diff_steps(path: &str, from: &[PlanStep], to: &[PlanStep], changes: &mut Vec<Change>) {
+    for before in from {
+        let path = format!("{}.steps[{}]", path, before.name);
+        match to.iter().find(|after| after.name == before.name) {
+            Some(after) => {
+                if before.needs != after.needs {
+                    changes.push(Change::Changed {
+                        path: path.clone(),
+                        field: "needs".to_string(),
+                        from: to_value(&before.needs),
+                        to: to_value(&after.needs),
+                    });
+                }
+
+                diff_node(path, &before.spec, &after.spec, changes);
+            }
+            None => {
+                changes.push(Change::Removed { path, node: before.spec.clone() });
+            }
+        }
+    }
+
+    for after in to {
+        if !from.iter().any(|before| before.name == after.name) {
+            let path = format!("{}.steps[{}]", path, after.name);
+            changes.push(Change::Added { path, node: after.spec.clone() });
+        }
+    }
+}
+
+
+fn kind(spec: &PlanSpec) -> &'static str {
+    match spec {
+        PlanSpec::Command { .. } => "command",
+        PlanSpec::Script { .. } => "script",
+        PlanSpec::TaskGroup { .. } => "parallel",
+        PlanSpec::TaskList { .. } => "serial",
+        PlanSpec::Steps { .. } => "steps",
+        PlanSpec::Matrix { .. } => "matrix",
+    }
+}
+
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn changes(from: Value, to: Value) -> Value {
+        let plan = |spec: Value| -> CreatePlan {
+            serde_json::from_value(json!({ "spec": spec })).unwrap()
+        };
+
+        to_value(&diff(&plan(from), &plan(to)))
+    }
+
+    /// A node as it appears in a change, with its defaults filled in.
+    fn node(spec: &Value) -> Value {
+        to_value(&serde_json::from_value::<PlanSpec>(spec.clone()).unwrap())
+    }
+
+    #[test]
+    fn children_are_aligned_on_common_subsequence() {
+        let a = json!({"args": ["a"]});
+        let b = json!({"args": ["b"]});
+        let c = json!({"args": ["c"]});
+        let x = json!({"args": ["x"]});
+
+        assert_eq!(
+            changes(json!({"serial": [a, b, c]}), json!({"serial": [a, x, b, c]})),
+            json!([{"change": "added", "path": "spec.serial[1]", "node": node(&x)}])
+        );
+        assert_eq!(
+            changes(json!({"serial": [a, b, c]}), json!({"serial": [a, c]})),
+            json!([{"change": "removed", "path": "spec.serial[1]", "node": node(&b)}])
+        );
+        assert_eq!(
+            changes(json!({"parallel": [a, b, c]}), json!({"parallel": [a, x, c]})),
+            json!([{
+                "change": "changed",
+                "path": "spec.parallel[1]",
+                "field": "args",
+                "from": ["b"],
+                "to": ["x"],
+            }])
+        );
+    }
+
+    #[test]
+    fn unmatched_children_beyond_a_gap_are_added_or_removed() {
+        let a = json!({"args": ["a"]});
+        let b = json!({"args": ["b"]});
+        let x = json!({"args": ["x"]});
+        let y = json!({"args": ["y"]});
+
+        assert_eq!(
+            changes(json!({"serial": [a, b]}), json!({"serial": [x, y, b]})),
+            json!([
+                {"change": "changed", "path": "spec.serial[0]", "field": "args", "from": ["a"], "to": ["x"]},
+                {"change": "added", "path": "spec.serial[1]", "node": node(&y)},
+            ])
+        );
+        assert_eq!(changes(json!({"serial": [a, b]}), json!({"serial": [a, b]})), json!([]));
+    }
+}
@@ -1,11 +1,20 @@
 use futures::stream::Stream;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::process::ExitStatus;
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender};
 use std::task::{Context, Poll};
-use tokio::sync::{Mutex, Notify};
-use tokio::io::AsyncBufReadExt;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::process::{ChildStderr, ChildStdin, ChildStdout};
+use tokio::signal::unix::{signal, SignalKind};
 
 use crate::error::Error;
 
@@ -13,17 +22,56 @@ use crate::error::Error;
 #[derive(Debug)]
 pub struct Process {
     inner: Mutex<ProcessState>,
+    /// Open while a command with streamed stdin is running
+    stdin: Mutex<Option<ChildStdin>>,
     output: Notify,
     exited: Notify,
 }
 
 impl Process {
     pub fn new() -> Self {
+        Self::with_log(OutputLog::Memory(vec![]), false)
+    }
+
+    /// Create a process whose output is written to a log file rather than
+    /// kept in memory.
+    pub fn spooled(path: PathBuf) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|err| Error::StorageFailed(Arc::new(err)))?;
+        }
+
+        let file = File::create(&path)
+            .map_err(|err| Error::StorageFailed(Arc::new(err)))?;
+        let writer = Some(spawn_writer(path.clone(), file));
+        Ok(Self::with_log(OutputLog::File { path, writer, len: 0, flushed: 0 }, false))
+    }
+
+    /// Open the log file of a process that has already finished, so that its
+    /// output can be streamed again.
+    pub async fn replay(path: PathBuf) -> Result<Self, Error> {
+        let counted = path.clone();
+        let len = tokio::task::spawn_blocking(move || {
+            let file = File::open(counted)?;
+            Ok(BufReader::new(file).lines().count())
+        }).await.map_err(|_| Error::Terminated)?;
+        let len = len.map_err(|err| Error::StorageFailed(Arc::new(err)))?;
+        Ok(Self::with_log(OutputLog::File { path, writer: None, len, flushed: len }, true))
+    }
+
+    fn with_log(log: OutputLog, closed: bool) -> Self {
         Self {
             inner: Mutex::new(ProcessState {
-                output: vec![],
+                output: log,
                 status: None,
+                pid: None,
+                killed: false,
+                closed,
+                started: None,
+                duration: None,
+                rusage: None,
             }),
+            stdin: Mutex::new(None),
             output: Notify::new(),
             exited: Notify::new(),
         }
@@ -31,29 +79,119 @@ impl Process {
 
     pub async fn run(
         self: Arc<Self>,
-        args: &[String],
+        spec: &CommandSpec,
         verbose: bool
     ) -> Result<(), Error> {
+        let Some((program, args)) = spec.args.split_first() else {
+            self.close().await;
+            let err = std::io::Error::new(std::io::ErrorKind::InvalidInput, "no command to run");
+            return Err(Error::CommandFailed(Arc::new(err)));
+        };
+
+        // Spawned without tokio, which would otherwise reap the child
+        // itself and lose its resource usage
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+
+        if spec.clear_env {
+            command.env_clear();
+        }
+
+        command.envs(&spec.env);
+
+        if let Some(ref cwd) = spec.cwd {
+            command.current_dir(cwd);
+        }
+
+        let stdin = match spec.stdin {
+            None => Stdio::null(),
+            Some(Stdin::Text(_)) | Some(Stdin::Stream) => Stdio::piped(),
+            Some(Stdin::File(ref path)) => {
+                // Relative paths are relative to the command's directory
+                let path = match spec.cwd {
+                    Some(ref cwd) => Path::new(cwd).join(path),
+                    None => PathBuf::from(path),
+                };
+
+                match File::open(path) {
+                    Ok(file) => Stdio::from(file),
+                    Err(err) => {
+                        self.close().await;
+                        return Err(Error::CommandFailed(Arc::new(err)));
+                    }
+                }
+            }
+        };
+
+        // Listen before spawning so that the command's exit is not missed
+        let mut exits = match signal(SignalKind::child()) {
+            Ok(exits) => exits,
+            Err(err) => {
+                self.close().await;
+                return Err(Error::CommandFailed(Arc::new(err)));
+            }
+        };
+
+        // Hold the state lock across spawning so a concurrent terminate
+        // either prevents the spawn or sees the pid
+        let mut inner = self.inner.lock().await;
+        if inner.killed {
+            inner.closed = true;
+            self.output.notify_waiters();
+            return Err(Error::Terminated);
+        }
+
+        // Put the command in its own process group so that signals reach
+        // everything it spawns
+        command.process_group(0);
+        let spawned = command
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+
+        let mut process = match spawned {
+            Ok(process) => process,
+            Err(err) => {
+                inner.closed = true;
+                self.output.notify_waiters();
+                return Err(Error::CommandFailed(Arc::new(err)));
+            }
+        };
+
+        let pid = process.id();
+        inner.pid = Some(pid);
+        inner.started = Some(Instant::now());
+        drop(inner);
 
-        let mut process = tokio::process::Command::new(&args[0])
-            .args(&args[1..])
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .map_err(|err| Error::CommandFailed(Arc::new(err)))?;
+        match (&spec.stdin, process.stdin.take()) {
+            (Some(Stdin::Text(text)), Some(stdin)) => {
+                // Commands that exit without reading all of it are fine
+                let text = text.clone();
+                let mut stdin = ChildStdin::from_std(stdin).expect("failed to register stdin");
+                tokio::spawn(async move {
+                    let _ = stdin.write_all(text.as_bytes()).await;
+                });
+            }
+            (Some(Stdin::Stream), Some(stdin)) => {
+                let stdin = ChildStdin::from_std(stdin).expect("failed to register stdin");
+                *self.stdin.lock().await = Some(stdin);
+            }
+            _ => {}
+        }
 
         let stdout = process.stdout.take().expect("failed to get stdout");
         let stderr = process.stderr.take().expect("failed to get stderr");
+        let stdout = ChildStdout::from_std(stdout).expect("failed to register stdout");
+        let stderr = ChildStderr::from_std(stderr).expect("failed to register stderr");
 
         let mut stdout = tokio::io::BufReader::new(stdout).lines();
         let mut stderr = tokio::io::BufReader::new(stderr).lines();
 
         let self_clone = self.clone();
-        tokio::spawn(async move {
+        let stdout = tokio::spawn(async move {
             while let Some(line) = stdout.next_line().await.unwrap() {
-                let mut inner = self_clone.inner.lock().await;
-                inner.output.push(Output::Stdout(line.clone()));
-                self_clone.output.notify_waiters();
+                self_clone.push(OutputEvent::Stdout(line.clone())).await;
                 if verbose {
                     eprintln!("{}", line);
                 }
@@ -61,74 +199,611 @@ impl Process {
         });
 
         let self_clone = self.clone();
-        tokio::spawn(async move {
+        let stderr = tokio::spawn(async move {
             while let Some(line) = stderr.next_line().await.unwrap() {
-                let mut inner = self_clone.inner.lock().await;
-                inner.output.push(Output::Stderr(line.clone()));
-                self_clone.output.notify_waiters();
+                self_clone.push(OutputEvent::Stderr(line.clone())).await;
                 if verbose {
                     eprintln!("{}", line);
                 }
             }
         });
 
-        let status = process.wait().await
-            .map_err(|err| Error::CommandFailed(Arc::new(err)))?;
-        let mut inner = self.inner.lock().await;
-        inner.status = Some(status);
+        // Finish the output with the exit record once both pipes are drained
+        // and the process has exited, in whichever order that happens
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            let _ = stdout.await;
+            let _ = stderr.await;
+            self_clone.wait().await;
+
+            let exit = {
+                let inner = self_clone.inner.lock().await;
+                OutputEvent::Exit {
+                    code: inner.status.and_then(|status| status.code()),
+                    signal: inner.status.and_then(|status| status.signal()),
+                    duration: inner.duration.unwrap_or_default(),
+                }
+            };
+
+            self_clone.push(exit).await;
+            self_clone.close().await;
+        });
+
+        // Check for the exit whenever any child exits. Reaping under the
+        // state lock keeps terminate from signalling the pid once it is
+        // reaped and may be reused.
+        let status = loop {
+            let mut inner = self.inner.lock().await;
+            match try_wait4(pid) {
+                Ok(Some((status, rusage))) => {
+                    inner.status = Some(status);
+                    inner.rusage = Some(rusage);
+                    inner.duration = inner.started.map(|started| started.elapsed());
+                    break status;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    drop(inner);
+                    self.close().await;
+                    return Err(Error::CommandFailed(Arc::new(err)));
+                }
+            }
+            drop(inner);
+
+            if exits.recv().await.is_none() {
+                self.close().await;
+                return Err(Error::Terminated);
+            }
+        };
+
+        self.stdin.lock().await.take();
         self.exited.notify_waiters();
-        Ok(())
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::ExitFailure(status))
+        }
+    }
+
+    /// Write to the stdin of a command that streams it, optionally closing
+    /// it afterwards. Fails if stdin is not open.
+    pub async fn write_stdin(&self, data: &[u8], close: bool) -> Result<(), Error> {
+        let mut stdin = self.stdin.lock().await;
+        let pipe = match stdin.as_mut() {
+            Some(pipe) => pipe,
+            None => {
+                return Err(Error::StdinClosed);
+            }
+        };
+
+        let written = match pipe.write_all(data).await {
+            Ok(_) => pipe.flush().await,
+            Err(err) => Err(err),
+        };
+
+        if close || written.is_err() {
+            stdin.take();
+        }
+
+        written.map_err(|err| Error::CommandFailed(Arc::new(err)))
+    }
+
+    async fn push(&self, event: OutputEvent) {
+        let mut inner = self.inner.lock().await;
+        let time = inner.started.map(|started| started.elapsed()).unwrap_or_default();
+        inner.output.push(time, event);
+        self.output.notify_waiters();
+    }
+
+    /// Number of output entries written so far.
+    pub async fn output_len(&self) -> usize {
+        self.inner.lock().await.output.len()
+    }
+
+    /// Close the output once everything written to it is on disk, which
+    /// also closes the log file.
+    async fn close(&self) {
+        let flushing = {
+            let mut inner = self.inner.lock().await;
+            let flushing = inner.output.flush();
+            inner.output.close();
+            flushing
+        };
+
+        if let Some((len, done)) = flushing {
+            let _ = done.await;
+            self.inner.lock().await.output.flushed_to(len);
+        }
+
+        self.inner.lock().await.closed = true;
+        self.output.notify_waiters();
+    }
+
+    /// Wait for the output entry at `index`, or `None` once the output is
+    /// closed before reaching it.
+    async fn next_output(
+        &self,
+        index: usize,
+        reader: &mut Option<LogReader>
+    ) -> Option<Output> {
+        let path = loop {
+            let notified = self.output.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let flushing = {
+                let mut inner = self.inner.lock().await;
+                if index < inner.output.readable() {
+                    match inner.output {
+                        OutputLog::Memory(ref output) => {
+                            return output.get(index).cloned();
+                        }
+                        OutputLog::File { ref path, .. } => {
+                            break path.clone();
+                        }
+                    }
+                } else if index < inner.output.len() {
+                    inner.output.flush()
+                } else if inner.closed {
+                    return None;
+                } else {
+                    None
+                }
+            };
+
+            // Without a flush of its own, the entry is being flushed by close
+            match flushing {
+                Some((len, done)) => {
+                    let _ = done.await;
+                    self.inner.lock().await.output.flushed_to(len);
+                }
+                None => notified.await,
+            }
+        };
+
+        // Read outside the state lock and off the runtime, since reopening
+        // the reader rescans the file up to `index`
+        let mut log_reader = reader.take();
+        let (output, log_reader) = tokio::task::spawn_blocking(move || {
+            let output = read_log(&path, index, &mut log_reader);
+            (output, log_reader)
+        }).await.ok()?;
+
+        *reader = log_reader;
+        output
+    }
+
+    pub async fn status(&self) -> Option<ExitStatus> {
+        self.inner.lock().await.status
+    }
+
+    pub async fn rusage(&self) -> Option<Rusage> {
+        self.inner.lock().await.rusage.clone()
+    }
+
+    pub async fn wait(&self) {
+        loop {
+            let notified = self.exited.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.inner.lock().await.status.is_some() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Send SIGTERM to the process group, then SIGKILL if it has not exited
+    /// once the grace period is over.
+    pub async fn terminate(&self, grace: Duration) {
+        let pid = {
+            let mut inner = self.inner.lock().await;
+            inner.killed = true;
+            match (inner.pid, inner.status) {
+                (Some(pid), None) => pid,
+                _ => {
+                    return;
+                }
+            }
+        };
+
+        signal_group(pid, libc::SIGTERM);
+        if tokio::time::timeout(grace, self.wait()).await.is_err() {
+            signal_group(pid, libc::SIGKILL);
+        }
     }
 }
 
+impl Default for Process {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-#[derive(Debug)]
+
+#[derive(Clone, Debug, Default)]
+pub struct CommandSpec {
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<String>,
+    pub clear_env: bool,
+    /// Standard input of the command; `None` connects it to /dev/null
+    pub stdin: Option<Stdin>,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stdin {
+    /// Inline text written to the command, then closed
+    Text(String),
+    /// File the command reads, relative to its working directory
+    File(String),
+    /// Left open for writing through the task's stdin endpoint
+    Stream,
+}
+
+
+/// Resource usage of an exited command. Descendants are included once the
+/// command has waited for them; orphans that outlive it are not.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Rusage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Largest resident set size, in kilobytes
+    pub max_rss: u64,
+    /// Blocks read and written through the file system
+    pub block_input: u64,
+    pub block_output: u64,
+    pub voluntary_context_switches: u64,
+    pub involuntary_context_switches: u64,
+}
+
+impl Rusage {
+    fn from_raw(usage: &libc::rusage) -> Self {
+        let time = |time: libc::timeval| {
+            Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+        };
+
+        Self {
+            user_time: time(usage.ru_utime),
+            system_time: time(usage.ru_stime),
+            max_rss: usage.ru_maxrss as u64,
+            block_input: usage.ru_inblock as u64,
+            block_output: usage.ru_oublock as u64,
+            voluntary_context_switches: usage.ru_nvcsw as u64,
+            involuntary_context_switches: usage.ru_nivcsw as u64,
+        }
+    }
+
+    /// Add the usage of another process. Everything is summed except the
+    /// resident set size, which keeps the largest.
+    pub fn add(&mut self, other: &Rusage) {
+        self.user_time += other.user_time;
+        self.system_time += other.system_time;
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.block_input += other.block_input;
+        self.block_output += other.block_output;
+        self.voluntary_context_switches += other.voluntary_context_switches;
+        self.involuntary_context_switches += other.involuntary_context_switches;
+    }
+}
+
+
+/// Stream of a process's output that waits for new lines to be written
+/// rather than polling for them.
 pub struct OutputStream {
-    process: Arc<Process>,
-    index: usize,
+    inner: Pin<Box<dyn Stream<Item = Output> + Send>>,
 }
 
 impl OutputStream {
-    pub fn new(process: Arc<Process>) -> Self {
-        Self { process, index: 0 }
+    /// Stream the output of a process, starting from the entry with sequence
+    /// number `start`.
+    pub fn new(process: Arc<Process>, start: usize) -> Self {
+        let state = (process, start, None);
+        let inner = futures::stream::unfold(state, |(process, index, mut reader)| {
+            async move {
+                let output = process.next_output(index, &mut reader).await?;
+                Some((output, (process, index + 1, reader)))
+            }
+        });
+
+        Self { inner: Box::pin(inner) }
     }
 }
 
 impl Stream for OutputStream {
     type Item = Output;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let this = self.get_mut();
-        let process = match this.process.inner.try_lock() {
-            Ok(process) => process,
-            Err(_) => {
-                cx.waker().wake_by_ref();
-                return Poll::Pending;
-            }
-        };
-
-        if let Some(output) = process.output.get(this.index) {
-            this.index += 1;
-            Poll::Ready(Some(output.clone()))
-        } else if process.status.is_some() {
-            Poll::Ready(None)
-        } else {
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
     }
 }
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum Output {
+pub struct Output {
+    pub seq: u64,
+    /// Time since the process started
+    pub time: Duration,
+    pub event: OutputEvent,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OutputEvent {
     Stdout(String),
     Stderr(String),
+    /// Always the last entry of a process that ran
+    Exit {
+        code: Option<i32>,
+        signal: Option<i32>,
+        duration: Duration,
+    },
 }
 
 
 #[derive(Debug)]
 struct ProcessState {
-    output: Vec<Output>,
+    output: OutputLog,
     status: Option<ExitStatus>,
+    pid: Option<u32>,
+    killed: bool,
+    closed: bool,
+    started: Option<Instant>,
+    duration: Option<Duration>,
+    rusage: Option<Rusage>,
+}
+
+
+#[derive(Debug)]
+enum OutputLog {
+    Memory(Vec<Output>),
+    /// Lines are written by a thread of their own and buffered there, and
+    /// only flushed once the log is closed or a reader reaches the entries
+    /// that are not on disk yet
+    File {
+        path: PathBuf,
+        /// Dropped once the log is closed, which closes the file
+        writer: Option<Sender<LogWrite>>,
+        len: usize,
+        flushed: usize,
+    },
+}
+
+impl OutputLog {
+    fn len(&self) -> usize {
+        match self {
+            OutputLog::Memory(output) => output.len(),
+            OutputLog::File { len, .. } => *len,
+        }
+    }
+
+    /// Number of entries that can be read back.
+    fn readable(&self) -> usize {
+        match self {
+            OutputLog::Memory(output) => output.len(),
+            OutputLog::File { flushed, .. } => *flushed,
+        }
+    }
+
+    fn push(&mut self, time: Duration, event: OutputEvent) {
+        let output = Output { seq: self.len() as u64, time, event };
+        match self {
+            OutputLog::Memory(log) => log.push(output),
+            OutputLog::File { writer: Some(writer), len, .. } => {
+                let mut line = serde_json::to_vec(&output)
+                    .expect("failed to serialize output");
+                line.push(b'\n');
+                if writer.send(LogWrite::Line(line)).is_ok() {
+                    *len += 1;
+                }
+            }
+            OutputLog::File { .. } => {}
+        }
+    }
+
+    /// Ask the writer to flush the entries that are not on disk yet. The
+    /// receiver completes once the entries before the returned length are.
+    fn flush(&mut self) -> Option<(usize, oneshot::Receiver<()>)> {
+        match self {
+            OutputLog::File { writer: Some(writer), len, flushed, .. } if *flushed < *len => {
+                let (done, flushing) = oneshot::channel();
+                writer.send(LogWrite::Flush(done)).ok()?;
+                Some((*len, flushing))
+            }
+            _ => None,
+        }
+    }
+
+    fn flushed_to(&mut self, len: usize) {
+        if let OutputLog::File { flushed, .. } = self {
+            *flushed = len.max(*flushed);
+        }
+    }
+
+    fn close(&mut self) {
+        if let OutputLog::File { writer, .. } = self {
+            writer.take();
+        }
+    }
+}
+
+
+#[derive(Debug)]
+enum LogWrite {
+    Line(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+
+/// Write the lines of a log file on a thread of its own until the log is
+/// closed. Lines after a failed write are dropped.
+fn spawn_writer(path: PathBuf, file: File) -> Sender<LogWrite> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        let mut file = Some(BufWriter::new(file));
+        for write in receiver {
+            let (result, done) = match (write, file.as_mut()) {
+                (LogWrite::Line(line), Some(writer)) => (writer.write_all(&line), None),
+                (LogWrite::Flush(done), Some(writer)) => (writer.flush(), Some(done)),
+                (LogWrite::Line(_), None) => (Ok(()), None),
+                (LogWrite::Flush(done), None) => (Ok(()), Some(done)),
+            };
+
+            if let Err(err) = result {
+                eprintln!("Failed to write output to {:?}: {}", path, err);
+                file = None;
+            }
+
+            if let Some(done) = done {
+                let _ = done.send(());
+            }
+        }
+
+        if let Some(Err(err)) = file.as_mut().map(|file| file.flush()) {
+            eprintln!("Failed to write output to {:?}: {}", path, err);
+        }
+    });
+
+    sender
+}
+
+
+/// Read the entry at `index` of a log file. The file is read sequentially
+/// through `reader`, which is reopened when it is not positioned at `index`.
+fn read_log(path: &Path, index: usize, reader: &mut Option<LogReader>) -> Option<Output> {
+    if reader.as_ref().is_none_or(|reader| reader.next != index) {
+        *reader = Some(LogReader {
+            reader: BufReader::new(File::open(path).ok()?),
+            next: 0,
+        });
+    }
+
+    let reader = reader.as_mut()?;
+    let mut line = String::new();
+    while reader.next <= index {
+        line.clear();
+        reader.reader.read_line(&mut line).ok()?;
+        reader.next += 1;
+    }
+
+    serde_json::from_str(&line).ok()
+}
+
+
+#[derive(Debug)]
+struct LogReader {
+    reader: BufReader<File>,
+    next: usize,
+}
+
+
+/// Reap a child if it has exited, returning its status and resource usage.
+fn try_wait4(pid: u32) -> std::io::Result<Option<(ExitStatus, Rusage)>> {
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, for which all zeroes is valid
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        // SAFETY: both pointers refer to live locals for the whole call
+        let result = unsafe {
+            libc::wait4(pid as libc::pid_t, &mut status, libc::WNOHANG, &mut usage)
+        };
+        if result == 0 {
+            return Ok(None);
+        } else if result > 0 {
+            return Ok(Some((ExitStatus::from_raw(status), Rusage::from_raw(&usage))));
+        }
+
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill has no memory safety requirements; a negative pid
+    // addresses the process group led by the command
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use futures::task::ArcWake;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct WakeCounter(AtomicUsize);
+
+    impl ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_tail_does_not_spin() {
+        let process = Arc::new(Process::new());
+        let mut stream = OutputStream::new(process.clone(), 0);
+        let wakes = Arc::new(WakeCounter::default());
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+
+        process.push(OutputEvent::Stdout("line".into())).await;
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            stream.poll_next_unpin(&mut cx),
+            Poll::Ready(Some(Output { seq: 0, event: OutputEvent::Stdout(line), .. }))
+                if line == "line"
+        ));
+
+        process.close().await;
+        assert!(matches!(stream.poll_next_unpin(&mut cx), Poll::Ready(None)));
+    }
+
+    #[tokio::test]
+    async fn spooled_output_is_read_back() {
+        let path = std::env::temp_dir()
+            .join(format!("poultry-{}", uuid::Uuid::new_v4()))
+            .join("output.log");
+        let process = Arc::new(Process::spooled(path.clone()).unwrap());
+        let mut stream = OutputStream::new(process.clone(), 0);
+
+        process.push(OutputEvent::Stdout("first".into())).await;
+        assert!(matches!(
+            stream.next().await,
+            Some(Output { seq: 0, event: OutputEvent::Stdout(line), .. }) if line == "first"
+        ));
+
+        process.push(OutputEvent::Stderr("second".into())).await;
+        process.close().await;
+        assert!(matches!(
+            stream.next().await,
+            Some(Output { seq: 1, event: OutputEvent::Stderr(line), .. }) if line == "second"
+        ));
+        assert!(stream.next().await.is_none());
+
+        let replayed = Arc::new(Process::replay(path.clone()).await.unwrap());
+        assert_eq!(replayed.output_len().await, 2);
+        let replayed: Vec<_> = OutputStream::new(replayed, 1).collect().await;
+        assert_eq!(replayed.len(), 1);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 }
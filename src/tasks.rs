@@ -1,6 +1,15 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
+use crate::plans::RetryPolicy;
+use crate::process::{CommandSpec, Rusage, Stdin};
+
+
+/// Shell that runs scripts which do not name one.
+pub const DEFAULT_SHELL: &str = "sh";
+
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CreateTask {
@@ -11,9 +20,40 @@ pub struct CreateTask {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Task {
     pub id: Uuid,
+    /// Tells apart children of steps and matrices
+    #[serde(default)]
+    pub name: Option<String>,
     pub plan: Option<TaskPlan>,
     pub spec: TaskSpec,
     pub status: TaskStatus,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub attempts: Vec<TaskAttempt>,
+    /// Parameter values the plan was run with, on the root task of a plan
+    #[serde(default)]
+    pub params: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
+    /// When the task first ran or waited on its children
+    #[serde(default)]
+    pub started_at: Option<SystemTime>,
+    #[serde(default)]
+    pub finished_at: Option<SystemTime>,
+    /// Time from start to finish, or so far if the task is still in progress
+    #[serde(default)]
+    pub duration: Option<Duration>,
+    /// Resource usage of every attempt of a command, or the total of a
+    /// parent's descendants
+    #[serde(default)]
+    pub rusage: Option<Rusage>,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskAttempt {
+    pub status: TaskStatus,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
 }
 
 
@@ -27,19 +67,157 @@ pub struct TaskPlan {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum TaskSpec {
-    Command { args: Vec<String> },
-    TaskGroup { parallel: Vec<Uuid> },
-    TaskList { serial: Vec<Uuid> },
+    Command {
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        clear_env: bool,
+        #[serde(default)]
+        stdin: Option<Stdin>,
+        #[serde(default)]
+        timeout: Option<u64>,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    Script {
+        script: String,
+        #[serde(default)]
+        shell: Option<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        clear_env: bool,
+        #[serde(default)]
+        stdin: Option<Stdin>,
+        #[serde(default)]
+        timeout: Option<u64>,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    TaskGroup {
+        parallel: Vec<Uuid>,
+        #[serde(default)]
+        max_parallel: Option<usize>,
+        #[serde(default)]
+        fail_fast: bool,
+        #[serde(default)]
+        continue_on_error: bool,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    TaskList {
+        serial: Vec<Uuid>,
+        #[serde(default)]
+        fail_fast: bool,
+        #[serde(default)]
+        continue_on_error: bool,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+    Steps {
+        steps: Vec<TaskStep>,
+        #[serde(default)]
+        retry: Option<RetryPolicy>,
+    },
+}
+
+impl TaskSpec {
+    /// The process to run for a command or script, or `None` for tasks
+    /// that only run children.
+    pub fn command(&self) -> Option<CommandSpec> {
+        match self {
+            TaskSpec::Command { args, env, cwd, clear_env, stdin, .. } => {
+                Some(CommandSpec {
+                    args: args.clone(),
+                    env: env.clone(),
+                    cwd: cwd.clone(),
+                    clear_env: *clear_env,
+                    stdin: stdin.clone(),
+                })
+            }
+            TaskSpec::Script { script, shell, env, cwd, clear_env, stdin, .. } => {
+                let shell = shell.as_deref().unwrap_or(DEFAULT_SHELL);
+                Some(CommandSpec {
+                    args: vec![
+                        shell.to_string(),
+                        "-e".to_string(),
+                        "-u".to_string(),
+                        "-c".to_string(),
+                        script.clone(),
+                    ],
+                    env: env.clone(),
+                    cwd: cwd.clone(),
+                    clear_env: *clear_env,
+                    stdin: stdin.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn timeout(&self) -> Option<u64> {
+        match self {
+            TaskSpec::Command { timeout, .. } | TaskSpec::Script { timeout, .. } => *timeout,
+            _ => None,
+        }
+    }
+
+    pub fn children(&self) -> Vec<Uuid> {
+        match self {
+            TaskSpec::Command { .. } | TaskSpec::Script { .. } => vec![],
+            TaskSpec::TaskGroup { parallel, .. } => parallel.clone(),
+            TaskSpec::TaskList { serial, .. } => serial.clone(),
+            TaskSpec::Steps { steps, .. } => steps.iter().map(|step| step.task).collect(),
+        }
+    }
+
+    pub fn retry(&self) -> Option<&RetryPolicy> {
+        match self {
+            TaskSpec::Command { retry, .. } => retry.as_ref(),
+            TaskSpec::Script { retry, .. } => retry.as_ref(),
+            TaskSpec::TaskGroup { retry, .. } => retry.as_ref(),
+            TaskSpec::TaskList { retry, .. } => retry.as_ref(),
+            TaskSpec::Steps { retry, .. } => retry.as_ref(),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskStep {
+    pub name: String,
+    pub needs: Vec<String>,
+    pub task: Uuid,
 }
 
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum TaskStatus {
     Pending,
+    Queued,
     Running,
     Waiting,
     Success,
     Failure,
+    TimedOut,
+    Cancelled,
+    Interrupted,
+}
+
+impl TaskStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self,
+            TaskStatus::Success |
+            TaskStatus::Failure |
+            TaskStatus::TimedOut |
+            TaskStatus::Cancelled |
+            TaskStatus::Interrupted)
+    }
 }
 
 